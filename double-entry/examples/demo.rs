@@ -0,0 +1,75 @@
+//! A card's whole life on the ledger: purchases retried with an idempotency
+//! key, a bill and its payment, recovery from a snapshot, a block, a
+//! replacement and the audit trail, printed as it goes.
+
+use rust_decimal_macros::dec;
+
+use authorizer::{audit, clock, command, controls, error, events, ledger, scoring, snapshot};
+
+fn main() -> Result<(), error::Error> {
+    let mut ledger = ledger::Ledger::new()
+        .with_snapshot_interval(4)
+        .with_clock(clock::Clock::Fixed {
+            now: time::OffsetDateTime::now_utc(),
+        })
+        .with_scoring(scoring::ScoringConfig {
+            flag_threshold: dec!(0.50),
+            decline_threshold: Some(dec!(0.80)),
+        });
+    let events = events::InMemorySink::default();
+    ledger.subscribe(events.clone());
+    ledger.issue_card(dec!(1000.00))?;
+    ledger.activate_card()?;
+    let purchase = command::Command::Purchase {
+        merchant: "Burguer King".to_string(),
+        amount: dec!(20.00),
+        mcc: Some(5814),
+        channel: controls::Channel::InPerson,
+    };
+    let cardholder = audit::Audit::new(audit::Actor::Cardholder, "cli", "");
+    ledger.execute("purchase-1", purchase.clone(), cardholder.clone())?;
+    ledger.execute("purchase-1", purchase, cardholder)?; // retried, posts nothing
+    ledger.advance_clock(time::Duration::days(30));
+    ledger.close_bill()?;
+    ledger.process_payment(dec!(20.00))?;
+
+    println!(
+        "card: {:#?}, journal: {:#?}, book accounts: {:#?}",
+        ledger.get_balance(),
+        ledger.journal(),
+        ledger.accounts()
+    );
+    println!(
+        "current limit statement: {:#?}, current limit now: {}",
+        ledger.statement(&ledger::BookAccount::AssetCurrentLimit)?,
+        ledger.balance_at(&ledger::BookAccount::AssetCurrentLimit, ledger.now())?
+    );
+
+    let snapshot_path = std::env::temp_dir().join("authorizer-snapshot.json");
+    ledger.latest_snapshot().unwrap().save(&snapshot_path)?;
+    let snapshot = snapshot::Snapshot::load(&snapshot_path)?;
+    let recovered = ledger::Ledger::recover_verified(snapshot, ledger.journal().to_vec())?;
+    println!(
+        "recovered from snapshot: {}",
+        recovered.accounts() == ledger.accounts()
+    );
+
+    ledger.block_card("suspected fraud".to_string())?;
+    ledger.unblock_card("cardholder confirmed purchases".to_string())?;
+    ledger.replace_card("card lost".to_string())?;
+    ledger.activate_card()?;
+    for card in ledger.replaced_cards().iter().chain([ledger.card()]) {
+        println!(
+            "card {}: {:?}, history: {:#?}",
+            card.id(),
+            card.status(),
+            card.status_history()
+        );
+    }
+    ledger.cancel_card("account closed".to_string())?;
+    println!("audit trail: {:#?}", ledger.audit_trail(None));
+    for event in events.events() {
+        println!("{}", serde_json::to_string(&event).unwrap());
+    }
+    Ok(())
+}
@@ -0,0 +1,132 @@
+//! Who asked for each movement and why, and the hash chain over the journal.
+
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use sha2::{Digest, Sha256};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::controls::SpendingControls;
+use crate::error;
+use crate::ledger::{CardStatus, Entry};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Actor {
+    System,
+    Cardholder,
+    Operator,
+}
+
+/// Who sent a command, through which channel (`api`, `cli`, `batch`...)
+/// and why. Stored on every entry the command posts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Audit {
+    pub actor: Actor,
+    pub channel: String,
+    pub reason: String,
+}
+
+impl Audit {
+    pub fn new(actor: Actor, channel: &str, reason: &str) -> Self {
+        Audit {
+            actor,
+            channel: channel.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Postings the ledger makes on its own, like settlements and payouts.
+    pub fn system() -> Self {
+        Audit::new(Actor::System, "internal", "")
+    }
+}
+
+impl Default for Audit {
+    fn default() -> Self {
+        Audit::system()
+    }
+}
+
+/// What an audited command did. Only movements are hash-chained, the
+/// other changes are kept with the card and the controls.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    Posted {
+        movement_id: Uuid,
+        hash: Option<String>, // of the movement's last entry
+    },
+    CardStatusChanged {
+        card_id: Uuid,
+        status: CardStatus,
+    },
+    SpendingControlsUpdated {
+        controls: SpendingControls,
+    },
+}
+
+/// One line of the audit trail: what was done and who asked for it.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    #[serde_as(as = "Rfc3339")]
+    pub at: OffsetDateTime,
+    #[serde(flatten)]
+    pub action: Action,
+    #[serde(flatten)]
+    pub audit: Audit,
+}
+
+/// The entry's hash: SHA-256, in hex, of the previous entry's hash followed
+/// by the entry itself as JSON without its own hash. Changing any entry, or
+/// removing or reordering entries, changes every hash after it.
+pub fn hash(previous: Option<&str>, entry: &Entry) -> String {
+    let unhashed = Entry {
+        hash: None,
+        ..entry.clone()
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(previous.unwrap_or_default());
+    hasher.update(serde_json::to_vec(&unhashed).expect("entries always serialize"));
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Whether `entry`, at `offset` in the journal, follows the entry hashed
+/// `previous`. Entries written before hashing was introduced have neither
+/// hash nor audit, but from `chained_since`, the offset of the first hashed
+/// entry, every entry must carry a valid hash.
+pub fn chained(
+    previous: Option<&str>,
+    entry: &Entry,
+    offset: usize,
+    chained_since: Option<usize>,
+) -> bool {
+    match &entry.hash {
+        Some(expected) => *expected == hash(previous, entry),
+        None => {
+            previous.is_none()
+                && entry.audit.is_none()
+                && chained_since.is_none_or(|since| offset < since)
+        }
+    }
+}
+
+/// Checks every entry against the one before it. `chained_since` is kept
+/// with the ledger snapshot; without it, a journal stripped of every hash
+/// and audit can't be told apart from one written before auditing.
+pub fn verify(journal: &[Entry], chained_since: Option<usize>) -> Result<(), error::Error> {
+    let mut previous: Option<&str> = None;
+    for (offset, entry) in journal.iter().enumerate() {
+        if !chained(previous, entry, offset, chained_since) {
+            return Err(error::Error::JournalTampered { offset });
+        }
+        previous = entry.hash.as_deref();
+    }
+    Ok(())
+}
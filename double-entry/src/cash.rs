@@ -0,0 +1,47 @@
+//! Cash advance terms: sub-limit, upfront fee and daily interest.
+
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+
+/// Cash advances draw on the card limit too, but only up to `limit_ratio` of
+/// `max_limit`. Unlike purchases they pay a fee upfront and accrue interest
+/// every day from the day they are taken until they are paid back.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CashAdvanceTerms {
+    pub limit_ratio: Decimal,
+    pub fee_rate: Decimal,
+    pub min_fee: Decimal,
+    pub daily_interest_rate: Decimal,
+}
+
+impl Default for CashAdvanceTerms {
+    fn default() -> Self {
+        CashAdvanceTerms {
+            limit_ratio: dec!(0.20),
+            fee_rate: dec!(0.03),
+            min_fee: dec!(5.00),
+            daily_interest_rate: dec!(0.0008),
+        }
+    }
+}
+
+impl CashAdvanceTerms {
+    pub fn limit(&self, max_limit: Decimal) -> Decimal {
+        (max_limit * self.limit_ratio).round_dp(2)
+    }
+
+    pub fn fee(&self, amount: Decimal) -> Decimal {
+        (amount * self.fee_rate).round_dp(2).max(self.min_fee)
+    }
+
+    /// Interest on each day's balance, rounded once at the end.
+    pub fn interest(&self, daily_balances: impl IntoIterator<Item = Decimal>) -> Decimal {
+        daily_balances
+            .into_iter()
+            .map(|balance| balance.max(Decimal::ZERO) * self.daily_interest_rate)
+            .sum::<Decimal>()
+            .round_dp(2)
+    }
+}
@@ -0,0 +1,35 @@
+//! Where the ledger takes the current time from.
+
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime};
+
+/// Where the ledger takes "now" from. A fixed clock only moves when advanced,
+/// which makes time-based rules reproducible in tests and simulations.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "clock", rename_all = "snake_case")]
+pub enum Clock {
+    System,
+    Fixed {
+        #[serde_as(as = "Rfc3339")]
+        now: OffsetDateTime,
+    },
+}
+
+impl Clock {
+    pub fn now(&self) -> OffsetDateTime {
+        match self {
+            Clock::System => OffsetDateTime::now_utc(),
+            Clock::Fixed { now } => *now,
+        }
+    }
+
+    /// The system clock advances on its own, so this only moves fixed clocks.
+    pub fn advance(&mut self, by: Duration) {
+        if let Clock::Fixed { now } = self {
+            *now += by;
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Ledger commands as clients send them, and what they produced.
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::controls::{Channel, SpendingControls};
+use crate::error;
+use crate::ledger::CardStatus;
+use crate::scoring::AnomalyScore;
+
+/// A ledger command as received from a client, kept alongside its outcome so a
+/// retry with the same idempotency key can be answered without running it again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    IssueCard {
+        max_limit: Decimal,
+    },
+    ActivateCard,
+    BlockCard {
+        reason: String,
+    },
+    UnblockCard {
+        reason: String,
+    },
+    CancelCard {
+        reason: String,
+    },
+    ReplaceCard {
+        reason: String,
+    },
+    Purchase {
+        merchant: String,
+        amount: Decimal,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mcc: Option<u16>,
+        #[serde(default)]
+        channel: Channel,
+    },
+    SetSpendingControls {
+        controls: SpendingControls,
+    },
+    Refund {
+        purchase_id: Uuid,
+    },
+    RedeemPoints {
+        points: Decimal,
+    },
+    CashAdvance {
+        atm: String,
+        amount: Decimal,
+    },
+    CloseBill,
+    Payment {
+        amount: Decimal,
+    },
+}
+
+/// What a command produced when it succeeded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    CardStatus(CardStatus),
+    Authorized(AnomalyScore),
+    Posted,
+    Updated,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Processed {
+    pub command: Command,
+    pub outcome: Result<Outcome, error::Error>,
+}
@@ -0,0 +1,151 @@
+//! Spending controls the cardholder sets on their own card.
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use std::collections::BTreeSet;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::audit::Audit;
+use crate::error;
+use crate::ledger::{BookAccount, Entry};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    #[default]
+    InPerson,
+    Online,
+}
+
+/// Limits the cardholder puts on their own card, on top of the credit limit.
+/// Caps are checked against the purchases already made in the current
+/// calendar day and month, as told by the ledger clock.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpendingControls {
+    pub per_transaction_max: Option<Decimal>,
+    pub daily_cap: Option<Decimal>,
+    pub monthly_cap: Option<Decimal>,
+    pub blocked_categories: BTreeSet<u16>, // merchant category codes
+    pub online_purchases: bool,
+}
+
+/// Controls as set at `changed_at`, and who set them.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ControlsChange {
+    pub controls: SpendingControls,
+    #[serde_as(as = "Rfc3339")]
+    pub changed_at: OffsetDateTime,
+    pub audit: Audit,
+}
+
+impl Default for SpendingControls {
+    fn default() -> Self {
+        SpendingControls {
+            per_transaction_max: None,
+            daily_cap: None,
+            monthly_cap: None,
+            blocked_categories: BTreeSet::new(),
+            online_purchases: true,
+        }
+    }
+}
+
+impl SpendingControls {
+    pub fn check_purchase(
+        &self,
+        spending: &MonthSpending,
+        amount: Decimal,
+        mcc: Option<u16>,
+        channel: &Channel,
+        now: OffsetDateTime,
+    ) -> Result<(), error::Error> {
+        if *channel == Channel::Online && !self.online_purchases {
+            return Err(error::Error::OnlinePurchasesDisabled);
+        }
+
+        if let Some(mcc) = mcc.filter(|mcc| self.blocked_categories.contains(mcc)) {
+            return Err(error::Error::MerchantCategoryBlocked { mcc });
+        }
+
+        if let Some(max) = self.per_transaction_max.filter(|max| amount > *max) {
+            return Err(error::Error::TransactionMaxExceeded { max, amount });
+        }
+
+        if let Some(cap) = self.daily_cap {
+            let spent = spending.spent_within(|date| date.date() == now.date());
+            if spent + amount > cap {
+                return Err(error::Error::DailyCapExceeded {
+                    cap,
+                    spent,
+                    requested: amount,
+                });
+            }
+        }
+
+        if let Some(cap) = self.monthly_cap {
+            let spent = spending.spent_within(|date| same_month(date, now));
+            if spent + amount > cap {
+                return Err(error::Error::MonthlyCapExceeded {
+                    cap,
+                    spent,
+                    requested: amount,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The purchases of the latest calendar month anything was bought in, kept
+/// up to date as entries are posted so the caps don't scan the journal.
+#[serde_as]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MonthSpending {
+    #[serde_as(as = "Vec<(_, Rfc3339, _)>")]
+    purchases: Vec<(Uuid, OffsetDateTime, Decimal)>,
+}
+
+impl MonthSpending {
+    // Refunds take the purchase back out.
+    pub fn record(&mut self, entry: &Entry) {
+        let is_refund = entry.debit_account == BookAccount::AssetCurrentLimit
+            && entry.credit_account == BookAccount::LiabilityCurrentLimitCp
+            && entry.merchant.is_some();
+        if let (true, Some(purchase_id)) = (is_refund, entry.reference) {
+            self.purchases.retain(|(id, _, _)| *id != purchase_id);
+            return;
+        }
+        let is_purchase = entry.debit_account == BookAccount::LiabilityCurrentLimitCp
+            && entry.credit_account == BookAccount::AssetCurrentLimit
+            && entry.merchant.is_some();
+        if !is_purchase {
+            return;
+        }
+        if let Some((_, last, _)) = self.purchases.last() {
+            if !same_month(*last, entry.post_date) {
+                self.purchases.clear();
+            }
+        }
+        self.purchases
+            .push((entry.id, entry.post_date, entry.amount));
+    }
+
+    // Sum of the purchases posted on the dates accepted by `within`.
+    fn spent_within(&self, within: impl Fn(OffsetDateTime) -> bool) -> Decimal {
+        self.purchases
+            .iter()
+            .filter(|(_, post_date, _)| within(*post_date))
+            .map(|(_, _, amount)| amount)
+            .sum()
+    }
+}
+
+fn same_month(a: OffsetDateTime, b: OffsetDateTime) -> bool {
+    (a.year(), a.month()) == (b.year(), b.month())
+}
@@ -1,27 +1,339 @@
+//! Why a command was rejected.
+
+use rust_decimal::Decimal;
+use serde::ser::{Error as _, SerializeMap};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
 use std::fmt;
+use uuid::Uuid;
+
+use crate::ledger::BookAccount;
+use crate::scoring::Contribution;
 
-#[derive(Debug)]
-pub enum Result {
-    InsufficientLimit,
+/// Serialized and deserialized with its `code` tag, named once below; the
+/// `message` is derived from the payload and ignored on the way in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(remote = "Self", tag = "code")]
+pub enum Error {
+    #[serde(rename = "insufficient_limit")]
+    InsufficientLimit {
+        requested: Decimal,
+        available: Decimal,
+    },
+    #[serde(rename = "card_already_issued")]
     CardAlreadyIssued,
+    #[serde(rename = "card_not_issued")]
     CardNotIssued,
+    #[serde(rename = "card_inactive")]
     CardInactive,
-    BookAccountNonExistent,
-    DoubleTransaction,
-    HighFrequencySmallInterval,
+    #[serde(rename = "card_already_active")]
+    CardAlreadyActive,
+    #[serde(rename = "card_blocked")]
+    CardBlocked,
+    #[serde(rename = "card_cancelled")]
+    CardCancelled,
+    #[serde(rename = "card_replaced")]
+    CardReplaced,
+    #[serde(rename = "book_acount_nonexistent")]
+    BookAccountNonExistent { account: BookAccount },
+    #[serde(rename = "doubled_transaction")]
+    DoubleTransaction {
+        merchant: Option<String>,
+        amount: Decimal,
+    },
+    #[serde(rename = "high_frequency_small_interval")]
+    HighFrequencySmallInterval {
+        movements: usize,
+        interval_seconds: i64,
+    },
+    #[serde(rename = "snapshot_unreadable")]
+    SnapshotUnreadable { reason: String },
+    #[serde(rename = "snapshot_mismatch")]
+    SnapshotMismatch { offset: usize, journal_len: usize },
+    #[serde(rename = "invalid_request")]
+    InvalidRequest { reason: String },
+    #[serde(rename = "idempotency_key_reused")]
+    IdempotencyKeyReused { key: String },
+    #[serde(rename = "journal_unreadable")]
+    JournalUnreadable { reason: String },
+    #[serde(rename = "export_failed")]
+    ExportFailed { reason: String },
+    #[serde(rename = "settlement_file_invalid")]
+    SettlementFileInvalid { line: usize, reason: String },
+    #[serde(rename = "anomalous_purchase")]
+    AnomalousPurchase {
+        score: Decimal,
+        contributions: Vec<Contribution>,
+    },
+    #[serde(rename = "online_purchases_disabled")]
+    OnlinePurchasesDisabled,
+    #[serde(rename = "merchant_category_blocked")]
+    MerchantCategoryBlocked { mcc: u16 },
+    #[serde(rename = "transaction_max_exceeded")]
+    TransactionMaxExceeded { max: Decimal, amount: Decimal },
+    #[serde(rename = "daily_cap_exceeded")]
+    DailyCapExceeded {
+        cap: Decimal,
+        spent: Decimal,
+        requested: Decimal,
+    },
+    #[serde(rename = "monthly_cap_exceeded")]
+    MonthlyCapExceeded {
+        cap: Decimal,
+        spent: Decimal,
+        requested: Decimal,
+    },
+    #[serde(rename = "event_sink_unavailable")]
+    EventSinkUnavailable { reason: String },
+    #[serde(rename = "purchase_not_found")]
+    PurchaseNotFound { id: Uuid },
+    #[serde(rename = "purchase_already_refunded")]
+    PurchaseAlreadyRefunded { id: Uuid },
+    #[serde(rename = "rewards_not_enabled")]
+    RewardsNotEnabled,
+    #[serde(rename = "invalid_points")]
+    InvalidPoints { points: Decimal },
+    #[serde(rename = "insufficient_points")]
+    InsufficientPoints {
+        requested: Decimal,
+        available: Decimal,
+    },
+    #[serde(rename = "redemption_exceeds_balance")]
+    RedemptionExceedsBalance { credit: Decimal, balance: Decimal },
+    #[serde(rename = "invalid_amount")]
+    InvalidAmount { amount: Decimal },
+    #[serde(rename = "cash_limit_exceeded")]
+    CashLimitExceeded {
+        requested: Decimal,
+        available: Decimal,
+    },
+    #[serde(rename = "storage_failed")]
+    StorageFailed { reason: String },
+    #[serde(rename = "journal_tampered")]
+    JournalTampered { offset: usize },
+    #[serde(rename = "simulation_config_invalid")]
+    SimulationConfigInvalid { reason: String },
+}
+
+impl Error {
+    /// Stable machine-readable code, kept as the strings clients already match
+    /// on. It's the `code` tag the error is serialized with.
+    pub fn code(&self) -> String {
+        match Error::serialize(self, serde_json::value::Serializer) {
+            Ok(Value::Object(mut fields)) => match fields.remove("code") {
+                Some(Value::String(code)) => code,
+                _ => unreachable!("errors are tagged with their code"),
+            },
+            _ => unreachable!("errors serialize to an object"),
+        }
+    }
 }
 
-impl std::error::Error for Result {}
-impl fmt::Display for Result {
+impl std::error::Error for Error {}
+impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            Result::InsufficientLimit => write!(f, "insufficient_limit"),
-            Result::CardAlreadyIssued => write!(f, "card_already_issued"),
-            Result::CardNotIssued => write!(f, "card_not_issued"),
-            Result::CardInactive => write!(f, "card_inactive"),
-            Result::BookAccountNonExistent => write!(f, "book_acount_nonexistent"),
-            Result::DoubleTransaction => write!(f, "doubled_transaction"),
-            Result::HighFrequencySmallInterval => write!(f, "high_frequency_small_interval"),
+        match self {
+            Error::InsufficientLimit {
+                requested,
+                available,
+            } => write!(
+                f,
+                "requested {} but only {} is available",
+                requested, available
+            ),
+            Error::CardAlreadyIssued => write!(f, "the card was already issued"),
+            Error::CardNotIssued => write!(f, "the card was not issued yet"),
+            Error::CardInactive => write!(f, "the card was not activated yet"),
+            Error::CardAlreadyActive => write!(f, "the card is already active"),
+            Error::CardBlocked => write!(f, "the card is blocked"),
+            Error::CardCancelled => write!(f, "the card was cancelled"),
+            Error::CardReplaced => write!(f, "the card was replaced by a new one"),
+            Error::BookAccountNonExistent { account } => {
+                write!(f, "book account {:?} does not exist", account)
+            }
+            Error::DoubleTransaction { merchant, amount } => write!(
+                f,
+                "same purchase of {} at {} was just processed",
+                amount,
+                merchant.as_deref().unwrap_or("unknown merchant")
+            ),
+            Error::HighFrequencySmallInterval {
+                movements,
+                interval_seconds,
+            } => write!(
+                f,
+                "{} movements in the last {} seconds",
+                movements, interval_seconds
+            ),
+            Error::SnapshotUnreadable { reason } => {
+                write!(f, "snapshot could not be read or written: {}", reason)
+            }
+            Error::SnapshotMismatch {
+                offset,
+                journal_len,
+            } => write!(
+                f,
+                "snapshot at offset {} does not match a journal of {} entries",
+                offset, journal_len
+            ),
+            Error::InvalidRequest { reason } => write!(f, "invalid request: {}", reason),
+            Error::IdempotencyKeyReused { key } => write!(
+                f,
+                "idempotency key {} was already used for a different command",
+                key
+            ),
+            Error::JournalUnreadable { reason } => {
+                write!(f, "journal could not be read: {}", reason)
+            }
+            Error::ExportFailed { reason } => write!(f, "export failed: {}", reason),
+            Error::EventSinkUnavailable { reason } => {
+                write!(f, "event sink could not be opened: {}", reason)
+            }
+            Error::PurchaseNotFound { id } => write!(f, "no purchase with id {}", id),
+            Error::PurchaseAlreadyRefunded { id } => {
+                write!(f, "purchase {} was already refunded", id)
+            }
+            Error::RewardsNotEnabled => write!(f, "the card has no rewards program"),
+            Error::InvalidPoints { points } => {
+                write!(f, "{} is not a positive whole number of points", points)
+            }
+            Error::InsufficientPoints {
+                requested,
+                available,
+            } => write!(
+                f,
+                "requested {} points but only {} are available",
+                requested, available
+            ),
+            Error::RedemptionExceedsBalance { credit, balance } => write!(
+                f,
+                "a credit of {} is more than the {} owed",
+                credit, balance
+            ),
+            Error::InvalidAmount { amount } => write!(f, "{} is not a positive amount", amount),
+            Error::CashLimitExceeded {
+                requested,
+                available,
+            } => write!(
+                f,
+                "requested {} in cash, fee included, but only {} is available",
+                requested, available
+            ),
+            Error::StorageFailed { reason } => write!(f, "storage failed: {}", reason),
+            Error::JournalTampered { offset } => {
+                write!(f, "journal entry {} does not match its hash", offset)
+            }
+            Error::SimulationConfigInvalid { reason } => {
+                write!(f, "invalid simulation config: {}", reason)
+            }
+            Error::SettlementFileInvalid { line, reason } => {
+                write!(f, "settlement file line {}: {}", line, reason)
+            }
+            Error::AnomalousPurchase { score, .. } => {
+                write!(f, "purchase looks unusual for this card (score {})", score)
+            }
+            Error::OnlinePurchasesDisabled => {
+                write!(f, "online purchases are turned off for this card")
+            }
+            Error::MerchantCategoryBlocked { mcc } => {
+                write!(f, "merchant category {} is blocked for this card", mcc)
+            }
+            Error::TransactionMaxExceeded { max, amount } => write!(
+                f,
+                "purchase of {} is above the per-transaction max of {}",
+                amount, max
+            ),
+            Error::DailyCapExceeded {
+                cap,
+                spent,
+                requested,
+            } => write!(
+                f,
+                "requested {} but {} of the daily cap of {} is already spent",
+                requested, spent, cap
+            ),
+            Error::MonthlyCapExceeded {
+                cap,
+                spent,
+                requested,
+            } => write!(
+                f,
+                "requested {} but {} of the monthly cap of {} is already spent",
+                requested, spent, cap
+            ),
+        }
+    }
+}
+
+// Serialized as `{"code": ..., "message": ..., <payload fields>}`.
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let fields = match Error::serialize(self, serde_json::value::Serializer) {
+            Ok(Value::Object(fields)) => fields,
+            Ok(_) => unreachable!("errors serialize to an object"),
+            Err(err) => return Err(S::Error::custom(err)),
+        };
+        let mut map = serializer.serialize_map(Some(fields.len() + 1))?;
+        map.serialize_entry("code", &fields["code"])?;
+        map.serialize_entry("message", &self.to_string())?;
+        for (field, value) in fields.iter().filter(|(field, _)| *field != "code") {
+            map.serialize_entry(field, value)?;
         }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Error {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Error::deserialize(deserializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+    use serde_json::json;
+
+    #[test]
+    fn error_json_representation() {
+        let error = Error::InsufficientLimit {
+            requested: dec!(30.00),
+            available: dec!(20.00),
+        };
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            json!({
+                "code": "insufficient_limit",
+                "message": "requested 30.00 but only 20.00 is available",
+                "requested": "30.00",
+                "available": "20.00",
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(Error::CardBlocked).unwrap(),
+            json!({"code": "card_blocked", "message": "the card is blocked"})
+        );
+    }
+
+    #[test]
+    fn error_json_round_trip() {
+        let error = Error::DoubleTransaction {
+            merchant: Some("Burguer King".to_string()),
+            amount: dec!(20.00),
+        };
+        let json = serde_json::to_string(&error).unwrap();
+        assert_eq!(serde_json::from_str::<Error>(&json).unwrap(), error);
+        assert_eq!(error.code(), "doubled_transaction");
+
+        let json = serde_json::to_string(&Error::CardBlocked).unwrap();
+        assert_eq!(
+            json,
+            r#"{"code":"card_blocked","message":"the card is blocked"}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Error>(&json).unwrap(),
+            Error::CardBlocked
+        );
     }
 }
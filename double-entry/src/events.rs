@@ -0,0 +1,236 @@
+//! Domain events published by the ledger, and sinks to subscribe with.
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::audit::Audit;
+use crate::controls::SpendingControls;
+use crate::error;
+use crate::ledger::CardStatus;
+use crate::scoring::AnomalyScore;
+
+/// What happened to the account, published by `Ledger` after each command
+/// so other systems can react without polling the journal. Commands answered
+/// from the idempotency store publish nothing the second time.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    CardIssued {
+        card_id: Uuid,
+        max_limit: Decimal,
+        #[serde_as(as = "Rfc3339")]
+        at: OffsetDateTime,
+    },
+    CardStatusChanged {
+        card_id: Uuid,
+        status: CardStatus,
+        reason: String,
+        #[serde(default)]
+        audit: Audit,
+        #[serde_as(as = "Rfc3339")]
+        at: OffsetDateTime,
+    },
+    SpendingControlsUpdated {
+        controls: SpendingControls,
+        #[serde(default)]
+        audit: Audit,
+        #[serde_as(as = "Rfc3339")]
+        at: OffsetDateTime,
+    },
+    PurchaseAuthorized {
+        movement_id: Uuid,
+        merchant: String,
+        amount: Decimal,
+        anomaly: AnomalyScore,
+        #[serde_as(as = "Rfc3339")]
+        at: OffsetDateTime,
+    },
+    PurchaseDeclined {
+        merchant: String,
+        amount: Decimal,
+        violation: error::Error,
+        #[serde_as(as = "Rfc3339")]
+        at: OffsetDateTime,
+    },
+    PurchaseRefunded {
+        movement_id: Uuid,
+        purchase_id: Uuid,
+        amount: Decimal,
+        #[serde_as(as = "Rfc3339")]
+        at: OffsetDateTime,
+    },
+    PointsRedeemed {
+        movement_id: Uuid,
+        points: Decimal,
+        credit: Decimal,
+        #[serde_as(as = "Rfc3339")]
+        at: OffsetDateTime,
+    },
+    CashAdvanceTaken {
+        movement_id: Uuid,
+        amount: Decimal,
+        fee: Decimal,
+        #[serde_as(as = "Rfc3339")]
+        at: OffsetDateTime,
+    },
+    InterestCharged {
+        movement_id: Uuid,
+        amount: Decimal,
+        #[serde_as(as = "Rfc3339")]
+        at: OffsetDateTime,
+    },
+    BillClosed {
+        movement_id: Uuid,
+        amount: Decimal,
+        #[serde_as(as = "Rfc3339")]
+        at: OffsetDateTime,
+    },
+    PaymentReceived {
+        movement_id: Uuid,
+        amount: Decimal,
+        #[serde_as(as = "Rfc3339")]
+        at: OffsetDateTime,
+    },
+}
+
+/// Receives every event the ledger publishes, see [`Ledger::subscribe`](crate::Ledger::subscribe).
+/// Subscribers are `Send` so the ledger can move between threads.
+pub trait Subscriber: Send {
+    fn notify(&mut self, event: &Event);
+}
+
+/// The subscribers of a ledger. They are not part of its state, so they are
+/// neither serialized nor compared.
+#[derive(Default)]
+pub(crate) struct Subscribers(Vec<Box<dyn Subscriber>>);
+
+impl Subscribers {
+    pub(crate) fn push(&mut self, subscriber: Box<dyn Subscriber>) {
+        self.0.push(subscriber);
+    }
+
+    pub(crate) fn publish(&mut self, event: Event) {
+        for subscriber in self.0.iter_mut() {
+            subscriber.notify(&event);
+        }
+    }
+}
+
+impl fmt::Debug for Subscribers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} subscribers", self.0.len())
+    }
+}
+
+impl PartialEq for Subscribers {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Subscribers {}
+
+/// Keeps every event in memory. Clones share the same events, so keep one
+/// clone to read them after handing the other to the ledger.
+#[derive(Debug, Default, Clone)]
+pub struct InMemorySink {
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl InMemorySink {
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+impl Subscriber for InMemorySink {
+    fn notify(&mut self, event: &Event) {
+        self.events.lock().unwrap().push(event.clone());
+    }
+}
+
+/// Appends one JSON event per line to a file.
+pub struct JsonlFileSink {
+    file: BufWriter<File>,
+}
+
+impl JsonlFileSink {
+    pub fn open(path: &Path) -> Result<Self, error::Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| error::Error::EventSinkUnavailable {
+                reason: err.to_string(),
+            })?;
+        Ok(JsonlFileSink {
+            file: BufWriter::new(file),
+        })
+    }
+}
+
+impl Subscriber for JsonlFileSink {
+    // The command already happened by the time it's published, so a failed
+    // write can only be reported, not undone.
+    fn notify(&mut self, event: &Event) {
+        let written = serde_json::to_writer(&mut self.file, event)
+            .map_err(|err| err.to_string())
+            .and_then(|_| writeln!(self.file).map_err(|err| err.to_string()))
+            .and_then(|_| self.file.flush().map_err(|err| err.to_string()));
+        if let Err(reason) = written {
+            eprintln!("could not write event to file: {}", reason);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+    use std::fs;
+    use time_macros::datetime;
+
+    #[test]
+    fn jsonl_file_sink_appends_one_event_per_line() {
+        let path = std::env::temp_dir().join(format!("events-{}.jsonl", Uuid::new_v4()));
+        let events = [
+            Event::PaymentReceived {
+                movement_id: Uuid::nil(),
+                amount: dec!(20.00),
+                at: datetime!(2023-03-01 10:00:00 UTC),
+            },
+            Event::PurchaseDeclined {
+                merchant: "Burguer King".to_string(),
+                amount: dec!(20.00),
+                violation: error::Error::CardBlocked,
+                at: datetime!(2023-03-01 10:05:00 UTC),
+            },
+        ];
+
+        let mut sink = JsonlFileSink::open(&path).unwrap();
+        for event in &events {
+            sink.notify(event);
+        }
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with(r#"{"event":"payment_received""#));
+        let read: Vec<Event> = lines
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(read, events);
+    }
+}
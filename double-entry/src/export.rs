@@ -0,0 +1,226 @@
+//! Journal exporters: CSV, hledger and general ledger.
+
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use time::format_description::well_known::Rfc3339;
+
+use crate::error;
+use crate::ledger::{BookAccount, Entry};
+
+// Usage:
+// cargo run -- export <csv|hledger|gl> journal.json [output]
+//
+// `journal.json` is a JSON array of entries, as returned by `GET /journal`.
+
+pub fn read_journal(path: &Path) -> Result<Vec<Entry>, error::Error> {
+    let json = fs::read_to_string(path).map_err(unreadable)?;
+    serde_json::from_str(&json).map_err(unreadable)
+}
+
+/// One row per leg: every entry becomes a debit row and a credit row.
+pub fn csv(journal: &[Entry]) -> String {
+    let mut out = String::from("id,post_date,account,debit,credit,merchant\n");
+    for entry in journal {
+        let post_date = rfc3339(entry);
+        let merchant = quote(entry.merchant.as_deref().unwrap_or(""));
+        writeln!(
+            out,
+            "{},{},{},{},,{}",
+            entry.id,
+            post_date,
+            account_name(&entry.debit_account),
+            entry.amount,
+            merchant
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "{},{},{},,{},{}",
+            entry.id,
+            post_date,
+            account_name(&entry.credit_account),
+            entry.amount,
+            merchant
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// Plain-text journal readable by ledger-cli and hledger. Entries sharing a
+/// movement id become one transaction; debits are positive postings.
+pub fn hledger(journal: &[Entry]) -> String {
+    let mut out = String::new();
+    let mut previous_id = None;
+    for entry in journal {
+        if previous_id != Some(entry.id) {
+            if previous_id.is_some() {
+                out.push('\n');
+            }
+            writeln!(
+                out,
+                "{} {}  ; id:{}",
+                entry.post_date.date(),
+                entry.merchant.as_deref().unwrap_or("movement"),
+                entry.id
+            )
+            .unwrap();
+            previous_id = Some(entry.id);
+        }
+        writeln!(
+            out,
+            "    {:<40}{:>14}",
+            account_name(&entry.debit_account),
+            entry.amount
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "    {:<40}{:>14}",
+            account_name(&entry.credit_account),
+            -entry.amount
+        )
+        .unwrap();
+    }
+    out
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct GeneralLedgerLine {
+    pub debits: Decimal,
+    pub credits: Decimal,
+    pub entries: usize,
+}
+
+impl GeneralLedgerLine {
+    /// Same sign convention as `Ledger::accounts`: credits add, debits subtract.
+    pub fn balance(&self) -> Decimal {
+        self.credits - self.debits
+    }
+}
+
+pub fn general_ledger(journal: &[Entry]) -> BTreeMap<BookAccount, GeneralLedgerLine> {
+    let mut summary: BTreeMap<BookAccount, GeneralLedgerLine> = BTreeMap::new();
+    for entry in journal {
+        let debit = summary.entry(entry.debit_account.clone()).or_default();
+        debit.debits += entry.amount;
+        debit.entries += 1;
+        let credit = summary.entry(entry.credit_account.clone()).or_default();
+        credit.credits += entry.amount;
+        credit.entries += 1;
+    }
+    summary
+}
+
+pub fn general_ledger_csv(journal: &[Entry]) -> String {
+    let mut out = String::from("account,entries,debits,credits,balance\n");
+    for (account, line) in general_ledger(journal) {
+        writeln!(
+            out,
+            "{},{},{},{},{}",
+            account_name(&account),
+            line.entries,
+            line.debits,
+            line.credits,
+            line.balance()
+        )
+        .unwrap();
+    }
+    out
+}
+
+fn account_name(account: &BookAccount) -> String {
+    serde_json::to_value(account)
+        .unwrap()
+        .as_str()
+        .unwrap()
+        .to_string()
+}
+
+fn rfc3339(entry: &Entry) -> String {
+    entry.post_date.format(&Rfc3339).unwrap()
+}
+
+fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn unreadable(err: impl std::fmt::Display) -> error::Error {
+    error::Error::JournalUnreadable {
+        reason: err.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::movement;
+    use rust_decimal_macros::dec;
+    use time_macros::datetime;
+
+    fn journal() -> Vec<Entry> {
+        let now = datetime!(2023-03-03 10:00:00 UTC);
+        let mut journal = movement::card_issued(dec!(1000.00), now);
+        journal.extend(movement::purchase(
+            "Burger King, Paulista".to_string(),
+            dec!(20.00),
+            now,
+        ));
+        journal
+    }
+
+    #[test]
+    fn csv_has_one_row_per_leg() {
+        let journal = journal();
+        let csv = csv(&journal);
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!(rows.len(), 1 + 2 * journal.len());
+        assert_eq!(
+            rows[5],
+            format!(
+                "{},2023-03-03T10:00:00Z,asset_settled,20.00,,\"Burger King, Paulista\"",
+                journal[2].id
+            )
+        );
+    }
+
+    #[test]
+    fn hledger_transactions_balance() {
+        let journal = journal();
+        let text = hledger(&journal);
+        assert_eq!(text.matches("2023-03-03 ").count(), 2);
+        let total: Decimal = text
+            .lines()
+            .filter(|line| line.starts_with("    "))
+            .map(|line| {
+                line.split_whitespace()
+                    .last()
+                    .unwrap()
+                    .parse::<Decimal>()
+                    .unwrap()
+            })
+            .sum();
+        assert_eq!(total, Decimal::ZERO);
+    }
+
+    #[test]
+    fn general_ledger_matches_ledger_balances() {
+        let summary = general_ledger(&journal());
+        assert_eq!(
+            summary[&BookAccount::AssetCurrentLimit].balance(),
+            dec!(-980.00)
+        );
+        assert_eq!(
+            summary[&BookAccount::LiabilityPayable].balance(),
+            dec!(19.60)
+        );
+        assert_eq!(summary[&BookAccount::LiabilityPayable].entries, 2);
+    }
+}
@@ -1,72 +1,270 @@
+//! The ledger itself: card, book accounts and journal.
+
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
-use std::collections::HashMap;
-use time::{ext::NumericalDuration, OffsetDateTime};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use std::collections::{BTreeMap, BTreeSet};
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime};
 use uuid::Uuid;
-use itertools::Itertools;
 
+use crate::audit::{self, Action, Actor, Audit, AuditRecord};
+use crate::cash::CashAdvanceTerms;
+use crate::clock::Clock;
+use crate::command::{Command, Outcome, Processed};
+use crate::controls::{Channel, ControlsChange, MonthSpending, SpendingControls};
 use crate::error;
+use crate::events::{Event, Subscriber, Subscribers};
 use crate::movement;
+use crate::rewards::RewardsProgram;
+use crate::rules::RuleState;
+use crate::scoring::{self, AnomalyScore, Profile, ScoringConfig};
+use crate::snapshot::Snapshot;
+
+const SNAPSHOT_INTERVAL: usize = 1000; // journal entries between snapshots
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+/// The chart of accounts. `off_balance` accounts track the card limit rather
+/// than money.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum BookAccount {
     AssetSettled,
     AssetCurrentLimit,
     AssetMaxCurrentLimit,
     AssetTransitoryBank,
+    AssetBank,
+    AssetRewardsPoints,
+    AssetCashAdvance, // cash taken, its fees and interest, until paid back
     LiabilityPayable,
     LiabilityReceivable,
     LiabilityCurrentLimitCp,
     LiabilityMaxCurrentLimitCp,
+    LiabilityRewardsPointsCp,
     EquityInterchange,
+    EquityRewards, // statement credits paid for with points
+    EquityFees,
+    EquityInterest,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct AccountInfo {
     amount: Decimal,
     version: u32,
     off_balance: bool,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+/// One journal line: moves `amount` from the debit account to the credit account.
+#[serde_as]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Entry {
     pub id: Uuid,
     pub amount: Decimal, // always positive
     pub debit_account: BookAccount,
     pub credit_account: BookAccount,
+    #[serde_as(as = "Rfc3339")]
     pub post_date: OffsetDateTime, // the day the entry actually ocurred
     pub merchant: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<Uuid>, // the movement this entry settles
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit: Option<Audit>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>, // chained to the previous entry, see `audit::hash`
+}
+
+/// One line of an account statement, see [`Ledger::statement`].
+#[serde_as]
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StatementLine {
+    pub id: Uuid,
+    pub amount: Decimal,  // negative when the account was debited
+    pub balance: Decimal, // account amount right after this entry
+    pub version: u32,
+    #[serde_as(as = "Rfc3339")]
+    pub post_date: OffsetDateTime,
+    pub merchant: Option<String>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// The physical or virtual card; the account and its balance outlive it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Card {
+    id: Uuid,
     status: CardStatus,
     max_limit: Decimal,
+    status_history: Vec<StatusChange>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum CardStatus {
     NotIssued,
     Inactive,
     Active,
+    Blocked,   // temporary, e.g. suspected fraud
+    Cancelled, // terminal
+    Replaced,  // terminal, a new card took over the account
+}
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatusChange {
+    pub status: CardStatus,
+    pub reason: String,
+    #[serde_as(as = "Rfc3339")]
+    pub changed_at: OffsetDateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit: Option<Audit>, // None for changes made before auditing
+}
+
+impl AccountInfo {
+    pub fn amount(&self) -> Decimal {
+        self.amount
+    }
+
+    /// Number of entries that touched the account.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn off_balance(&self) -> bool {
+        self.off_balance
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+impl Card {
+    fn issued(max_limit: Decimal, reason: String, now: OffsetDateTime, audit: &Audit) -> Self {
+        let mut card = Card {
+            id: Uuid::new_v4(),
+            status: CardStatus::NotIssued,
+            max_limit,
+            status_history: vec![],
+        };
+        card.set_status(CardStatus::Inactive, reason, now, audit);
+        card
+    }
+
+    fn set_status(
+        &mut self,
+        status: CardStatus,
+        reason: String,
+        now: OffsetDateTime,
+        audit: &Audit,
+    ) {
+        self.status_history.push(StatusChange {
+            status: status.clone(),
+            reason,
+            changed_at: now,
+            audit: Some(audit.clone()),
+        });
+        self.status = status;
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn status(&self) -> &CardStatus {
+        &self.status
+    }
+
+    pub fn status_history(&self) -> &[StatusChange] {
+        &self.status_history
+    }
+
+    fn status_changed(&self) -> Event {
+        let change = self.status_history.last().unwrap();
+        Event::CardStatusChanged {
+            card_id: self.id,
+            status: change.status.clone(),
+            reason: change.reason.clone(),
+            audit: change.audit.clone().unwrap_or_default(),
+            at: change.changed_at,
+        }
+    }
+}
+
+impl CardStatus {
+    // Why a card in this status can't be used or moved to the requested status.
+    fn rejection(&self) -> error::Error {
+        match self {
+            CardStatus::NotIssued => error::Error::CardNotIssued,
+            CardStatus::Inactive => error::Error::CardInactive,
+            CardStatus::Active => error::Error::CardAlreadyActive,
+            CardStatus::Blocked => error::Error::CardBlocked,
+            CardStatus::Cancelled => error::Error::CardCancelled,
+            CardStatus::Replaced => error::Error::CardReplaced,
+        }
+    }
+}
+
+/// A single card account: its card, book accounts and journal.
+///
+/// Every command goes through a method such as [`Ledger::process_purchase`],
+/// or through [`Ledger::execute`] to make retries safe.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Ledger {
     card: Card,
-    pub accounts: HashMap<BookAccount, AccountInfo>,
-    pub journal: Vec<Entry>,
+    replaced_cards: Vec<Card>,
+    rules: RuleState,
+    controls: SpendingControls,
+    controls_history: Vec<ControlsChange>,
+    profile: Profile,
+    spending: MonthSpending,
+    accounts: BTreeMap<BookAccount, AccountInfo>,
+    journal: Vec<Entry>,
+    #[serde(default)]
+    chained_since: Option<usize>, // offset of the first hashed entry, see `audit::verify`
+    #[serde(skip)]
+    latest_snapshot: Option<Snapshot>, // saved on its own, see `storage::save`
+    snapshot_interval: usize,
+    processed: BTreeMap<String, Processed>, // by idempotency key
+    #[serde(default)]
+    disputed: BTreeSet<Uuid>, // purchases whose payout is held
+    clock: Clock,
+    scoring: ScoringConfig,
+    rewards: Option<RewardsProgram>,
+    cash_terms: CashAdvanceTerms,
+    #[serde(skip)]
+    subscribers: Subscribers,
+    #[serde(skip)]
+    audit: Audit, // of the command being run
+}
+
+impl Default for Ledger {
+    fn default() -> Self {
+        Ledger::new()
+    }
 }
 
 impl Ledger {
     pub fn new() -> Self {
         Ledger {
             card: Card {
+                id: Uuid::nil(),
                 status: CardStatus::NotIssued,
                 max_limit: dec!(0.00),
+                status_history: vec![],
             },
+            replaced_cards: vec![],
+            rules: RuleState::default(),
+            controls: SpendingControls::default(),
+            controls_history: vec![],
+            profile: Profile::default(),
+            spending: MonthSpending::default(),
             journal: vec![],
-            accounts: HashMap::from([
+            chained_since: None,
+            latest_snapshot: None,
+            snapshot_interval: SNAPSHOT_INTERVAL,
+            processed: BTreeMap::new(),
+            disputed: BTreeSet::new(),
+            clock: Clock::System,
+            scoring: ScoringConfig::default(),
+            rewards: None,
+            cash_terms: CashAdvanceTerms::default(),
+            subscribers: Subscribers::default(),
+            audit: Audit::system(),
+            accounts: BTreeMap::from([
                 (
                     BookAccount::AssetSettled,
                     AccountInfo {
@@ -139,20 +337,386 @@ impl Ledger {
                         off_balance: false,
                     },
                 ),
+                (
+                    BookAccount::AssetBank,
+                    AccountInfo {
+                        amount: dec!(0.00),
+                        version: 0,
+                        off_balance: false,
+                    },
+                ),
+                (
+                    BookAccount::AssetRewardsPoints,
+                    AccountInfo {
+                        amount: dec!(0.00),
+                        version: 0,
+                        off_balance: true,
+                    },
+                ),
+                (
+                    BookAccount::LiabilityRewardsPointsCp,
+                    AccountInfo {
+                        amount: dec!(0.00),
+                        version: 0,
+                        off_balance: true,
+                    },
+                ),
+                (
+                    BookAccount::EquityRewards,
+                    AccountInfo {
+                        amount: dec!(0.00),
+                        version: 0,
+                        off_balance: false,
+                    },
+                ),
+                (
+                    BookAccount::AssetCashAdvance,
+                    AccountInfo {
+                        amount: dec!(0.00),
+                        version: 0,
+                        off_balance: false,
+                    },
+                ),
+                (
+                    BookAccount::EquityFees,
+                    AccountInfo {
+                        amount: dec!(0.00),
+                        version: 0,
+                        off_balance: false,
+                    },
+                ),
+                (
+                    BookAccount::EquityInterest,
+                    AccountInfo {
+                        amount: dec!(0.00),
+                        version: 0,
+                        off_balance: false,
+                    },
+                ),
             ]),
         }
     }
 
+    pub fn with_snapshot_interval(mut self, snapshot_interval: usize) -> Self {
+        self.snapshot_interval = snapshot_interval;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_scoring(mut self, scoring: ScoringConfig) -> Self {
+        self.scoring = scoring;
+        self
+    }
+
+    /// Without a program no points are earned and redeeming fails.
+    pub fn with_rewards(mut self, rewards: RewardsProgram) -> Self {
+        self.rewards = Some(rewards);
+        self
+    }
+
+    pub fn with_cash_advance_terms(mut self, cash_terms: CashAdvanceTerms) -> Self {
+        self.cash_terms = cash_terms;
+        self
+    }
+
+    pub fn subscribe(&mut self, subscriber: impl Subscriber + 'static) {
+        self.subscribers.push(Box::new(subscriber));
+    }
+
+    pub fn now(&self) -> OffsetDateTime {
+        self.clock.now()
+    }
+
+    pub fn advance_clock(&mut self, by: Duration) {
+        self.clock.advance(by);
+    }
+
+    /// Rebuilds the ledger by applying every journal entry from scratch. With
+    /// no snapshot to say where the hash chain starts, a journal stripped of
+    /// every hash and audit is taken as one written before auditing.
+    pub fn replay(card: Card, journal: Vec<Entry>) -> Result<Self, error::Error> {
+        let mut ledger = Ledger::new();
+        ledger.card = card;
+        ledger.process(journal)?;
+        Ok(ledger)
+    }
+
+    /// Restores the state covered by the snapshot and applies only the journal
+    /// entries written after it.
+    pub fn recover(snapshot: Snapshot, mut journal: Vec<Entry>) -> Result<Self, error::Error> {
+        if snapshot.offset > journal.len() {
+            return Err(error::Error::SnapshotMismatch {
+                offset: snapshot.offset,
+                journal_len: journal.len(),
+            });
+        }
+        let tail = journal.split_off(snapshot.offset);
+
+        let mut ledger = Ledger::new();
+        ledger.card = snapshot.card.clone();
+        ledger.replaced_cards = snapshot.replaced_cards.clone();
+        ledger.rules = snapshot.rules.clone();
+        ledger.controls = snapshot.controls.clone();
+        ledger.controls_history = snapshot.controls_history.clone();
+        ledger.profile = snapshot.profile.clone();
+        ledger.spending = snapshot.spending.clone();
+        ledger.accounts = snapshot.accounts.clone();
+        ledger.processed = snapshot.processed.clone();
+        ledger.disputed = snapshot.disputed.clone();
+        ledger.chained_since = snapshot
+            .chained_since
+            .or_else(|| journal.iter().position(|e| e.hash.is_some()));
+        ledger.journal = journal;
+        ledger.latest_snapshot = Some(snapshot);
+        ledger.process(tail)?;
+        Ok(ledger)
+    }
+
+    /// Same as `recover`, but also replays the whole journal and fails if both
+    /// ledgers disagree.
+    pub fn recover_verified(snapshot: Snapshot, journal: Vec<Entry>) -> Result<Self, error::Error> {
+        audit::verify(&journal, snapshot.chained_since)?;
+        let offset = snapshot.offset;
+        let replayed = Ledger::replay(snapshot.card.clone(), journal.clone())?;
+        let recovered = Ledger::recover(snapshot, journal)?;
+        if recovered.accounts != replayed.accounts
+            || recovered.rules != replayed.rules
+            || recovered.profile != replayed.profile
+            || recovered.spending != replayed.spending
+        {
+            return Err(error::Error::SnapshotMismatch {
+                offset,
+                journal_len: recovered.journal.len(),
+            });
+        }
+        Ok(recovered)
+    }
+
+    /// The current state. Also taken automatically, see `checkpoint`.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            offset: self.journal.len(),
+            card: self.card.clone(),
+            replaced_cards: self.replaced_cards.clone(),
+            accounts: self.accounts.clone(),
+            rules: self.rules.clone(),
+            controls: self.controls.clone(),
+            controls_history: self.controls_history.clone(),
+            profile: self.profile.clone(),
+            spending: self.spending.clone(),
+            processed: self.processed.clone(),
+            disputed: self.disputed.clone(),
+            chained_since: self.chained_since,
+        }
+    }
+
+    pub fn card(&self) -> &Card {
+        &self.card
+    }
+
+    pub fn replaced_cards(&self) -> &[Card] {
+        &self.replaced_cards
+    }
+
+    pub fn spending_controls(&self) -> &SpendingControls {
+        &self.controls
+    }
+
+    /// Every change of the spending controls, oldest first.
+    pub fn spending_controls_history(&self) -> &[ControlsChange] {
+        &self.controls_history
+    }
+
+    /// Cash still owed: advances, their fees and the interest charged on them.
+    pub fn cash_advance_balance(&self) -> Decimal {
+        -self.accounts[&BookAccount::AssetCashAdvance].amount()
+    }
+
+    /// What can still be taken in cash, fee included: the cash sub-limit left,
+    /// but never more than the available limit.
+    pub fn cash_available(&self) -> Decimal {
+        let sub_limit = self.cash_terms.limit(self.card.max_limit) - self.cash_advance_balance();
+        sub_limit.min(self.get_balance()).max(Decimal::ZERO)
+    }
+
+    /// Points that can still be redeemed.
+    pub fn points_balance(&self) -> Decimal {
+        -self.accounts[&BookAccount::AssetRewardsPoints].amount()
+    }
+
+    /// Every entry posted so far, oldest first.
+    pub fn journal(&self) -> &[Entry] {
+        &self.journal
+    }
+
+    pub fn accounts(&self) -> &BTreeMap<BookAccount, AccountInfo> {
+        &self.accounts
+    }
+
+    /// Purchases in dispute, whose payout is held until it's resolved.
+    pub fn disputed(&self) -> &BTreeSet<Uuid> {
+        &self.disputed
+    }
+
+    /// The last snapshot taken automatically, or recovered from. Only the
+    /// latest is kept.
+    pub fn latest_snapshot(&self) -> Option<&Snapshot> {
+        self.latest_snapshot.as_ref()
+    }
+
+    /// Available limit of the card.
     pub fn get_balance(&self) -> Decimal {
         self.accounts
             .get(&BookAccount::AssetCurrentLimit)
             .unwrap()
-            .amount
+            .amount()
             .abs()
     }
 
-    pub fn process(&mut self, entries: Vec<Entry>) -> Result<(), error::Result> {
+    /// Balance of `account` including every entry posted up to `timestamp`.
+    pub fn balance_at(
+        &self,
+        account: &BookAccount,
+        timestamp: OffsetDateTime,
+    ) -> Result<Decimal, error::Error> {
+        if !self.accounts.contains_key(account) {
+            return Err(error::Error::BookAccountNonExistent {
+                account: account.clone(),
+            });
+        }
+
+        Ok(self
+            .journal
+            .iter()
+            .filter(|e| e.post_date <= timestamp)
+            .fold(dec!(0.00), |balance, e| {
+                if &e.debit_account == account {
+                    balance - e.amount
+                } else if &e.credit_account == account {
+                    balance + e.amount
+                } else {
+                    balance
+                }
+            }))
+    }
+
+    /// Every entry that touched `account`, with the running balance.
+    pub fn statement(&self, account: &BookAccount) -> Result<Vec<StatementLine>, error::Error> {
+        if !self.accounts.contains_key(account) {
+            return Err(error::Error::BookAccountNonExistent {
+                account: account.clone(),
+            });
+        }
+
+        let mut balance = dec!(0.00);
+        let mut version = 0;
+        let mut lines = vec![];
+        for entry in &self.journal {
+            let amount = if &entry.debit_account == account {
+                -entry.amount
+            } else if &entry.credit_account == account {
+                entry.amount
+            } else {
+                continue;
+            };
+            balance += amount;
+            version += 1;
+
+            lines.push(StatementLine {
+                id: entry.id,
+                amount,
+                balance,
+                version,
+                post_date: entry.post_date,
+                merchant: entry.merchant.clone(),
+            });
+        }
+        Ok(lines)
+    }
+
+    /// One record per movement, card status change and spending controls
+    /// change, oldest first, optionally only those `actor` asked for. What
+    /// was done before auditing was introduced is left out.
+    pub fn audit_trail(&self, actor: Option<&Actor>) -> Vec<AuditRecord> {
+        let mut trail: Vec<AuditRecord> = vec![];
+        for entry in &self.journal {
+            let Some(audit) = &entry.audit else { continue };
+            match trail.last_mut() {
+                Some(AuditRecord {
+                    action: Action::Posted { movement_id, hash },
+                    ..
+                }) if *movement_id == entry.id => {
+                    *hash = entry.hash.clone();
+                }
+                _ => trail.push(AuditRecord {
+                    at: entry.post_date,
+                    action: Action::Posted {
+                        movement_id: entry.id,
+                        hash: entry.hash.clone(),
+                    },
+                    audit: audit.clone(),
+                }),
+            }
+        }
+        for card in self.replaced_cards.iter().chain([&self.card]) {
+            for change in &card.status_history {
+                let Some(audit) = &change.audit else { continue };
+                trail.push(AuditRecord {
+                    at: change.changed_at,
+                    action: Action::CardStatusChanged {
+                        card_id: card.id,
+                        status: change.status.clone(),
+                    },
+                    audit: audit.clone(),
+                });
+            }
+        }
+        for change in &self.controls_history {
+            trail.push(AuditRecord {
+                at: change.changed_at,
+                action: Action::SpendingControlsUpdated {
+                    controls: change.controls.clone(),
+                },
+                audit: change.audit.clone(),
+            });
+        }
+
+        // stable, so a movement comes before a change made at the same time
+        trail.sort_by_key(|record| record.at);
+        trail.retain(|record| actor.is_none() || actor == Some(&record.audit.actor));
+        trail
+    }
+
+    /// Stamps new entries with the audit of the command being run and chains
+    /// their hashes to the journal, then processes them.
+    pub(crate) fn post(&mut self, mut entries: Vec<Entry>) -> Result<(), error::Error> {
+        let mut previous = self.journal.last().and_then(|e| e.hash.clone());
+        for entry in entries.iter_mut() {
+            entry.audit = Some(self.audit.clone());
+            let hash = audit::hash(previous.as_deref(), entry);
+            entry.hash = Some(hash.clone());
+            previous = Some(hash);
+        }
+        self.process(entries)?;
+        self.checkpoint();
+        Ok(())
+    }
+
+    fn process(&mut self, entries: Vec<Entry>) -> Result<(), error::Error> {
         for entry in entries {
+            let offset = self.journal.len();
+            let previous = self.journal.last().and_then(|e| e.hash.as_deref());
+            if !audit::chained(previous, &entry, offset, self.chained_since) {
+                return Err(error::Error::JournalTampered { offset });
+            }
+            if entry.hash.is_some() && self.chained_since.is_none() {
+                self.chained_since = Some(offset);
+            }
+
             // update book accounts
             match self.accounts.get_mut(&entry.debit_account) {
                 Some(debit_account) => {
@@ -162,7 +726,11 @@ impl Ledger {
                         off_balance: debit_account.off_balance,
                     }
                 }
-                None => return Err(error::Result::BookAccountNonExistent),
+                None => {
+                    return Err(error::Error::BookAccountNonExistent {
+                        account: entry.debit_account,
+                    })
+                }
             };
             match self.accounts.get_mut(&entry.credit_account) {
                 Some(credit_account) => {
@@ -172,40 +740,254 @@ impl Ledger {
                         off_balance: credit_account.off_balance,
                     }
                 }
-                None => return Err(error::Result::BookAccountNonExistent),
+                None => {
+                    return Err(error::Error::BookAccountNonExistent {
+                        account: entry.credit_account,
+                    })
+                }
             };
 
-            // update journal
+            // update rule state, purchase history and journal
+            self.rules.record(&entry);
+            self.profile.record(&entry);
+            self.spending.record(&entry);
             self.journal.push(entry);
         }
         Ok(())
     }
 
-    pub fn issue_card(&mut self, max_limit: Decimal) -> Result<CardStatus, error::Result> {
+    /// Takes a snapshot once `snapshot_interval` entries were posted since the
+    /// last one, and retakes the last one whenever what the journal doesn't
+    /// record changed: the card, the spending controls, the idempotency keys
+    /// or the disputes. Recovering from the latest snapshot and the journal then never
+    /// loses a change the journal can't replay.
+    fn checkpoint(&mut self) {
+        let due = match &self.latest_snapshot {
+            None => self.journal.len() >= self.snapshot_interval,
+            Some(latest) => {
+                self.journal.len() - latest.offset >= self.snapshot_interval
+                    || latest.card != self.card
+                    || latest.replaced_cards.len() != self.replaced_cards.len()
+                    || latest.controls_history.len() != self.controls_history.len()
+                    || latest.processed.len() != self.processed.len()
+                    || latest.disputed != self.disputed
+            }
+        };
+        if due {
+            self.latest_snapshot = Some(self.snapshot());
+        }
+    }
+
+    /// Runs the command once per idempotency key: a retry with the same key and
+    /// payload gets the original outcome back without posting anything.
+    pub fn execute(
+        &mut self,
+        idempotency_key: &str,
+        command: Command,
+        audit: Audit,
+    ) -> Result<Outcome, error::Error> {
+        if let Some(processed) = self.processed.get(idempotency_key) {
+            if processed.command != command {
+                return Err(error::Error::IdempotencyKeyReused {
+                    key: idempotency_key.to_string(),
+                });
+            }
+            return processed.outcome.clone();
+        }
+
+        let outcome = self.run(command.clone(), audit);
+        self.processed.insert(
+            idempotency_key.to_string(),
+            Processed {
+                command,
+                outcome: outcome.clone(),
+            },
+        );
+        self.checkpoint();
+        outcome
+    }
+
+    /// Runs the command, stamping whatever it posts with `audit`. Calling the
+    /// command methods directly posts as [`Audit::system`].
+    pub fn run(&mut self, command: Command, audit: Audit) -> Result<Outcome, error::Error> {
+        self.audited(audit, |ledger| ledger.dispatch(command))
+    }
+
+    /// Calls `f` with whatever it posts stamped with `audit`, for the jobs
+    /// that post outside of a command, like settlement reconciliation.
+    pub fn audited<T>(&mut self, audit: Audit, f: impl FnOnce(&mut Ledger) -> T) -> T {
+        let previous = std::mem::replace(&mut self.audit, audit);
+        let result = f(self);
+        self.audit = previous;
+        result
+    }
+
+    fn dispatch(&mut self, command: Command) -> Result<Outcome, error::Error> {
+        match command {
+            Command::IssueCard { max_limit } => self.issue_card(max_limit).map(Outcome::CardStatus),
+            Command::ActivateCard => self.activate_card().map(Outcome::CardStatus),
+            Command::BlockCard { reason } => self.block_card(reason).map(Outcome::CardStatus),
+            Command::UnblockCard { reason } => self.unblock_card(reason).map(Outcome::CardStatus),
+            Command::CancelCard { reason } => self.cancel_card(reason).map(Outcome::CardStatus),
+            Command::ReplaceCard { reason } => self.replace_card(reason).map(Outcome::CardStatus),
+            Command::Purchase {
+                merchant,
+                amount,
+                mcc,
+                channel,
+            } => self
+                .process_purchase(merchant, amount, mcc, channel)
+                .map(Outcome::Authorized),
+            Command::SetSpendingControls { controls } => self
+                .set_spending_controls(controls)
+                .map(|_| Outcome::Updated),
+            Command::Refund { purchase_id } => {
+                self.process_refund(purchase_id).map(|_| Outcome::Posted)
+            }
+            Command::RedeemPoints { points } => self.redeem_points(points).map(|_| Outcome::Posted),
+            Command::CashAdvance { atm, amount } => self
+                .process_cash_advance(atm, amount)
+                .map(|_| Outcome::Posted),
+            Command::CloseBill => self.close_bill().map(|_| Outcome::Posted),
+            Command::Payment { amount } => self.process_payment(amount).map(|_| Outcome::Posted),
+        }
+    }
+
+    pub fn issue_card(&mut self, max_limit: Decimal) -> Result<CardStatus, error::Error> {
         match &self.card.status {
             CardStatus::NotIssued => {
-                let card = Card {
-                    status: CardStatus::Inactive,
-                    max_limit: max_limit.to_owned(),
-                };
-                self.card = card;
+                self.card = Card::issued(
+                    max_limit.to_owned(),
+                    "issued".to_string(),
+                    self.now(),
+                    &self.audit,
+                );
 
-                let entries = movement::card_issued(max_limit);
-                self.process(entries)?;
+                let entries = movement::card_issued(max_limit, self.now());
+                self.post(entries)?;
 
+                self.subscribers.publish(Event::CardIssued {
+                    card_id: self.card.id,
+                    max_limit,
+                    at: self.now(),
+                });
                 Ok(CardStatus::Inactive)
             }
-            _ => Err(error::Result::CardAlreadyIssued),
+            _ => Err(error::Error::CardAlreadyIssued),
         }
     }
 
-    pub fn activate_card(&mut self) -> Result<CardStatus, error::Result> {
+    pub fn activate_card(&mut self) -> Result<CardStatus, error::Error> {
         match &self.card.status {
-            CardStatus::NotIssued => Err(error::Result::CardNotIssued),
-            _ => {
-                self.card.status = CardStatus::Active;
+            CardStatus::Inactive => {
+                let now = self.now();
+                self.card.set_status(
+                    CardStatus::Active,
+                    "activated".to_string(),
+                    now,
+                    &self.audit,
+                );
+                self.subscribers.publish(self.card.status_changed());
+                self.checkpoint();
                 Ok(CardStatus::Active)
             }
+            status => Err(status.rejection()),
+        }
+    }
+
+    pub fn block_card(&mut self, reason: String) -> Result<CardStatus, error::Error> {
+        match &self.card.status {
+            CardStatus::Active => {
+                let now = self.now();
+                self.card
+                    .set_status(CardStatus::Blocked, reason, now, &self.audit);
+                self.subscribers.publish(self.card.status_changed());
+                self.checkpoint();
+                Ok(CardStatus::Blocked)
+            }
+            status => Err(status.rejection()),
+        }
+    }
+
+    pub fn unblock_card(&mut self, reason: String) -> Result<CardStatus, error::Error> {
+        match &self.card.status {
+            CardStatus::Blocked => {
+                let now = self.now();
+                self.card
+                    .set_status(CardStatus::Active, reason, now, &self.audit);
+                self.subscribers.publish(self.card.status_changed());
+                self.checkpoint();
+                Ok(CardStatus::Active)
+            }
+            status => Err(status.rejection()),
+        }
+    }
+
+    pub fn cancel_card(&mut self, reason: String) -> Result<CardStatus, error::Error> {
+        match &self.card.status {
+            CardStatus::Inactive | CardStatus::Active | CardStatus::Blocked => {
+                let now = self.now();
+                self.card
+                    .set_status(CardStatus::Cancelled, reason, now, &self.audit);
+                self.subscribers.publish(self.card.status_changed());
+                self.checkpoint();
+                Ok(CardStatus::Cancelled)
+            }
+            status => Err(status.rejection()),
+        }
+    }
+
+    /// The new card is issued inactive with the same limit; the balance stays
+    /// with the book accounts, so it carries over without posting any entry.
+    pub fn replace_card(&mut self, reason: String) -> Result<CardStatus, error::Error> {
+        match &self.card.status {
+            CardStatus::Inactive | CardStatus::Active | CardStatus::Blocked => {
+                let now = self.now();
+                let new_card = Card::issued(
+                    self.card.max_limit,
+                    format!("replaces card {}: {}", self.card.id, reason),
+                    now,
+                    &self.audit,
+                );
+                let mut old_card = std::mem::replace(&mut self.card, new_card);
+                old_card.set_status(CardStatus::Replaced, reason, now, &self.audit);
+                self.subscribers.publish(old_card.status_changed());
+                self.replaced_cards.push(old_card);
+                self.subscribers.publish(Event::CardIssued {
+                    card_id: self.card.id,
+                    max_limit: self.card.max_limit,
+                    at: now,
+                });
+                self.checkpoint();
+                Ok(CardStatus::Inactive)
+            }
+            status => Err(status.rejection()),
+        }
+    }
+
+    /// The controls belong to the account, so a replacement card keeps them.
+    pub fn set_spending_controls(
+        &mut self,
+        controls: SpendingControls,
+    ) -> Result<(), error::Error> {
+        match &self.card.status {
+            CardStatus::NotIssued => Err(error::Error::CardNotIssued),
+            CardStatus::Cancelled => Err(error::Error::CardCancelled),
+            _ => {
+                self.controls = controls;
+                self.controls_history.push(ControlsChange {
+                    controls: self.controls.clone(),
+                    changed_at: self.now(),
+                    audit: self.audit.clone(),
+                });
+                self.subscribers.publish(Event::SpendingControlsUpdated {
+                    controls: self.controls.clone(),
+                    audit: self.audit.clone(),
+                    at: self.now(),
+                });
+                self.checkpoint();
+                Ok(())
+            }
         }
     }
 
@@ -213,60 +995,325 @@ impl Ledger {
         &mut self,
         merchant: String,
         amount: Decimal,
-    ) -> Result<(), error::Result> {
+        mcc: Option<u16>,
+        channel: Channel,
+    ) -> Result<AnomalyScore, error::Error> {
+        let at = self.now();
+        let authorized = self.authorize_purchase(merchant.clone(), amount, mcc, channel);
+        let event = match &authorized {
+            Ok((movement_id, score)) => Event::PurchaseAuthorized {
+                movement_id: *movement_id,
+                merchant,
+                amount,
+                anomaly: score.clone(),
+                at,
+            },
+            Err(violation) => Event::PurchaseDeclined {
+                merchant,
+                amount,
+                violation: violation.clone(),
+                at,
+            },
+        };
+        self.subscribers.publish(event);
+        authorized.map(|(_, score)| score)
+    }
+
+    fn authorize_purchase(
+        &mut self,
+        merchant: String,
+        amount: Decimal,
+        mcc: Option<u16>,
+        channel: Channel,
+    ) -> Result<(Uuid, AnomalyScore), error::Error> {
         match &self.card.status {
             CardStatus::Active => {
+                if amount <= Decimal::ZERO {
+                    return Err(error::Error::InvalidAmount { amount });
+                }
                 let balance = self.get_balance();
                 if balance < amount {
-                    return Err(error::Result::InsufficientLimit);
+                    return Err(error::Error::InsufficientLimit {
+                        requested: amount,
+                        available: balance,
+                    });
                 }
+                self.controls
+                    .check_purchase(&self.spending, amount, mcc, &channel, self.now())?;
 
-                let entries = movement::purchase(merchant, amount);
-                match self.journal.last() {
-                    Some(last_journal_entry) => {
-                        let purchase_entry = entries.last().unwrap();
-                        if last_journal_entry.merchant == purchase_entry.merchant
-                            && last_journal_entry.amount == purchase_entry.amount
-                        {
-                            return Err(error::Result::DoubleTransaction);
-                        }
-
-                        let count = self.journal
-                            .iter()
-                            .filter(|e| {
-                                e.post_date
-                                    > OffsetDateTime::now_utc().checked_sub(2.minutes()).unwrap()
-                            })
-                            .map(|e| e.id)
-                            .unique()
-                            .count();
-                        if count >= 3 {
-                            return Err(error::Result::HighFrequencySmallInterval);
-                        }
+                let score =
+                    scoring::score(&self.profile, &merchant, amount, self.now(), &self.scoring);
+                if let Some(threshold) = self.scoring.decline_threshold {
+                    if score.score >= threshold {
+                        return Err(error::Error::AnomalousPurchase {
+                            score: score.score,
+                            contributions: score.contributions,
+                        });
                     }
-                    None => (),
-                };
+                }
 
-                self.process(entries)?;
+                let entries = movement::purchase(merchant, amount, self.now());
+                self.rules.check_purchase(&entries, self.now())?;
 
-                Ok(())
+                let movement_id = entries[0].id;
+                self.post(entries)?;
+
+                if let Some(rewards) = &self.rewards {
+                    let points = rewards.points(amount, mcc, self.now());
+                    if points > Decimal::ZERO {
+                        self.post(movement::points_earned(movement_id, points, self.now()))?;
+                    }
+                }
+
+                Ok((movement_id, score))
+            }
+            status => Err(status.rejection()),
+        }
+    }
+
+    // Where the purchase's limit leg is in the journal.
+    fn purchase_position(&self, purchase_id: Uuid) -> Result<usize, error::Error> {
+        self.journal
+            .iter()
+            .position(|e| {
+                e.id == purchase_id
+                    && e.merchant.is_some()
+                    && e.reference.is_none()
+                    && e.credit_account == BookAccount::AssetCurrentLimit
+            })
+            .ok_or(error::Error::PurchaseNotFound { id: purchase_id })
+    }
+
+    /// Holds the merchant payout of the purchase, see `payout::run`, until
+    /// the dispute is resolved.
+    pub fn open_dispute(&mut self, purchase_id: Uuid) -> Result<(), error::Error> {
+        self.purchase_position(purchase_id)?;
+        self.disputed.insert(purchase_id);
+        self.checkpoint();
+        Ok(())
+    }
+
+    /// Lets the next payout run pay the purchase. Resolving a purchase not in
+    /// dispute does nothing.
+    pub fn resolve_dispute(&mut self, purchase_id: Uuid) -> Result<(), error::Error> {
+        self.purchase_position(purchase_id)?;
+        self.disputed.remove(&purchase_id);
+        self.checkpoint();
+        Ok(())
+    }
+
+    /// Gives the whole purchase back: the amount owed to the merchant, the
+    /// interchange, the limit and the points it earned. A purchase already on
+    /// a closed bill is credited to the bill, and only the points not yet
+    /// redeemed are taken back.
+    pub fn process_refund(&mut self, purchase_id: Uuid) -> Result<(), error::Error> {
+        if self.card.status == CardStatus::NotIssued {
+            return Err(error::Error::CardNotIssued);
+        }
+
+        let position = self.purchase_position(purchase_id)?;
+        let amount = self.journal[position].amount;
+        if amount <= Decimal::ZERO {
+            return Err(error::Error::InvalidAmount { amount });
+        }
+        let refunded = self.journal.iter().any(|e| {
+            e.reference == Some(purchase_id) && e.debit_account == BookAccount::AssetCurrentLimit
+        });
+        if refunded {
+            return Err(error::Error::PurchaseAlreadyRefunded { id: purchase_id });
+        }
+
+        let purchase: Vec<Entry> = self
+            .journal
+            .iter()
+            .filter(|e| e.id == purchase_id && e.merchant.is_some() && e.reference.is_none())
+            .cloned()
+            .collect();
+        let billed = self.journal[position..].iter().any(|e| {
+            let accounts = [&e.debit_account, &e.credit_account];
+            accounts.contains(&&BookAccount::LiabilityReceivable)
+                && accounts.contains(&&BookAccount::AssetSettled)
+        });
+        let earned: Vec<Entry> = self
+            .journal
+            .iter()
+            .filter(|e| {
+                e.reference == Some(purchase_id)
+                    && e.debit_account == BookAccount::AssetRewardsPoints
+            })
+            .cloned()
+            .collect();
+
+        let mut entries = movement::reversal(&purchase, self.now());
+        if billed {
+            for entry in entries.iter_mut() {
+                if entry.credit_account == BookAccount::AssetSettled {
+                    entry.credit_account = BookAccount::LiabilityReceivable;
+                }
             }
-            CardStatus::NotIssued => Err(error::Result::CardNotIssued),
-            CardStatus::Inactive => Err(error::Result::CardInactive),
         }
+        let movement_id = entries[0].id;
+        self.post(entries)?;
+
+        let mut held = self.points_balance().max(Decimal::ZERO);
+        let mut points = movement::reversal(&earned, self.now());
+        for entry in points.iter_mut() {
+            entry.amount = entry.amount.min(held);
+            held -= entry.amount;
+        }
+        points.retain(|e| e.amount > Decimal::ZERO);
+        if !points.is_empty() {
+            self.post(points)?;
+        }
+
+        self.subscribers.publish(Event::PurchaseRefunded {
+            movement_id,
+            purchase_id,
+            amount,
+            at: self.now(),
+        });
+        Ok(())
     }
 
-    pub fn close_bill(&mut self) -> Result<(), error::Result> {
+    /// Turns points into a credit on the bill, at the program's point value.
+    /// The credit can't be more than what the cardholder owes.
+    pub fn redeem_points(&mut self, points: Decimal) -> Result<Decimal, error::Error> {
+        if self.card.status == CardStatus::NotIssued {
+            return Err(error::Error::CardNotIssued);
+        }
+        let Some(rewards) = &self.rewards else {
+            return Err(error::Error::RewardsNotEnabled);
+        };
+        if points <= Decimal::ZERO || !points.fract().is_zero() {
+            return Err(error::Error::InvalidPoints { points });
+        }
+
+        let available = self.points_balance();
+        if points > available {
+            return Err(error::Error::InsufficientPoints {
+                requested: points,
+                available,
+            });
+        }
+        let credit = rewards.credit_for(points);
+        let owed = -(self.accounts[&BookAccount::AssetSettled].amount()
+            + self.accounts[&BookAccount::LiabilityReceivable].amount());
+        if credit > owed {
+            return Err(error::Error::RedemptionExceedsBalance {
+                credit,
+                balance: owed,
+            });
+        }
+
+        let entries = movement::points_redeemed(points, credit, self.now());
+        let movement_id = entries[0].id;
+        self.post(entries)?;
+
+        self.subscribers.publish(Event::PointsRedeemed {
+            movement_id,
+            points,
+            credit,
+            at: self.now(),
+        });
+        Ok(credit)
+    }
+
+    pub fn process_cash_advance(
+        &mut self,
+        atm: String,
+        amount: Decimal,
+    ) -> Result<Decimal, error::Error> {
         match &self.card.status {
-            CardStatus::NotIssued => Err(error::Result::CardNotIssued),
+            CardStatus::Active => {
+                if amount <= Decimal::ZERO {
+                    return Err(error::Error::InvalidAmount { amount });
+                }
+                let fee = self.cash_terms.fee(amount);
+                let available = self.cash_available();
+                if amount + fee > available {
+                    return Err(error::Error::CashLimitExceeded {
+                        requested: amount + fee,
+                        available,
+                    });
+                }
+
+                let entries = movement::cash_advance(atm, amount, fee, self.now());
+                let movement_id = entries[0].id;
+                self.post(entries)?;
+
+                self.subscribers.publish(Event::CashAdvanceTaken {
+                    movement_id,
+                    amount,
+                    fee,
+                    at: self.now(),
+                });
+                Ok(fee)
+            }
+            status => Err(status.rejection()),
+        }
+    }
+
+    /// Charges interest on the cash advance balance for every whole day since
+    /// the last charge, or since the first advance. Called on every bill close.
+    pub fn accrue_interest(&mut self) -> Result<Decimal, error::Error> {
+        let since = self
+            .journal
+            .iter()
+            .rev()
+            .find(|e| e.credit_account == BookAccount::EquityInterest)
+            .or_else(|| {
+                self.journal
+                    .iter()
+                    .find(|e| e.debit_account == BookAccount::AssetCashAdvance)
+            })
+            .map(|e| e.post_date);
+        let Some(since) = since else {
+            return Ok(Decimal::ZERO);
+        };
+
+        let days = (self.now() - since).whole_days();
+        let mut daily_balances = vec![];
+        for day in 0..days {
+            let balance =
+                self.balance_at(&BookAccount::AssetCashAdvance, since + Duration::days(day))?;
+            daily_balances.push(-balance);
+        }
+        let interest = self.cash_terms.interest(daily_balances);
+        if interest > Decimal::ZERO {
+            let entries = movement::interest_charged(interest, self.now());
+            let movement_id = entries[0].id;
+            self.post(entries)?;
+            self.subscribers.publish(Event::InterestCharged {
+                movement_id,
+                amount: interest,
+                at: self.now(),
+            });
+        }
+        Ok(interest)
+    }
+
+    pub fn close_bill(&mut self) -> Result<(), error::Error> {
+        match &self.card.status {
+            CardStatus::NotIssued => Err(error::Error::CardNotIssued),
             _ => {
+                self.accrue_interest()?;
                 match self.accounts.get(&BookAccount::AssetSettled) {
                     Some(acc) => {
-                        let bill_amount = acc.amount.abs();
-                        let entries = movement::closed_bill(bill_amount);
-                        self.process(entries)?;
+                        let bill_amount = -acc.amount; // negative is a credit
+                        let entries = movement::closed_bill(bill_amount, self.now());
+                        let movement_id = entries[0].id;
+                        self.post(entries)?;
+                        self.subscribers.publish(Event::BillClosed {
+                            movement_id,
+                            amount: bill_amount,
+                            at: self.now(),
+                        });
+                    }
+                    None => {
+                        return Err(error::Error::BookAccountNonExistent {
+                            account: BookAccount::AssetSettled,
+                        })
                     }
-                    None => return Err(error::Result::BookAccountNonExistent),
                 }
 
                 Ok(())
@@ -274,15 +1321,763 @@ impl Ledger {
         }
     }
 
-    pub fn process_payment(&mut self, payment_amount: Decimal) -> Result<(), error::Result> {
+    pub fn process_payment(&mut self, payment_amount: Decimal) -> Result<(), error::Error> {
         match &self.card.status {
-            CardStatus::NotIssued => Err(error::Result::CardNotIssued),
+            CardStatus::NotIssued => Err(error::Error::CardNotIssued),
+            _ if payment_amount <= Decimal::ZERO => Err(error::Error::InvalidAmount {
+                amount: payment_amount,
+            }),
             _ => {
-                let entries = movement::payment(payment_amount);
-                self.process(entries)?;
+                let entries = movement::payment(payment_amount, self.now());
+                let movement_id = entries[0].id;
+                self.post(entries)?;
+
+                // cash advances keep accruing interest, so they are paid back first
+                let to_cash = payment_amount.min(self.cash_advance_balance());
+                if to_cash > Decimal::ZERO {
+                    let entries =
+                        movement::payment_to_cash_advance(movement_id, to_cash, self.now());
+                    self.post(entries)?;
+                }
+                self.subscribers.publish(Event::PaymentReceived {
+                    movement_id,
+                    amount: payment_amount,
+                    at: self.now(),
+                });
 
                 Ok(())
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::InMemorySink;
+    use crate::rewards::RewardsProgram;
+    use serde_json::json;
+    use time_macros::datetime;
+
+    #[test]
+    fn entry_json_representation() {
+        let entry = Entry {
+            id: Uuid::nil(),
+            amount: dec!(20.00),
+            debit_account: BookAccount::LiabilityCurrentLimitCp,
+            credit_account: BookAccount::AssetCurrentLimit,
+            post_date: datetime!(2023-03-03 10:00:00 UTC),
+            merchant: Some("Burguer King".to_string()),
+            reference: None,
+            audit: None,
+            hash: None,
+        };
+
+        let value = serde_json::to_value(&entry).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "id": "00000000-0000-0000-0000-000000000000",
+                "amount": "20.00",
+                "debit_account": "liability_current_limit_cp",
+                "credit_account": "asset_current_limit",
+                "post_date": "2023-03-03T10:00:00Z",
+                "merchant": "Burguer King",
+            })
+        );
+        assert_eq!(serde_json::from_value::<Entry>(value).unwrap(), entry);
+    }
+
+    #[test]
+    fn card_status_json_representation() {
+        assert_eq!(
+            serde_json::to_string(&CardStatus::NotIssued).unwrap(),
+            "\"not_issued\""
+        );
+        assert_eq!(
+            serde_json::from_str::<CardStatus>("\"active\"").unwrap(),
+            CardStatus::Active
+        );
+    }
+
+    #[test]
+    fn ledger_round_trip() {
+        let mut ledger = Ledger::new();
+        ledger.issue_card(dec!(1000.00)).unwrap();
+        ledger.activate_card().unwrap();
+        ledger
+            .process_purchase(
+                "Burguer King".to_string(),
+                dec!(20.00),
+                None,
+                Channel::InPerson,
+            )
+            .unwrap();
+        ledger.close_bill().unwrap();
+        ledger.process_payment(dec!(20.00)).unwrap();
+
+        let json = serde_json::to_string(&ledger).unwrap();
+        assert_eq!(serde_json::from_str::<Ledger>(&json).unwrap(), ledger);
+    }
+
+    #[test]
+    fn balances_at_a_point_in_time_and_statement() {
+        let issued_at = datetime!(2023-03-01 12:00:00 UTC);
+        let mut ledger = Ledger::new().with_clock(Clock::Fixed { now: issued_at });
+        ledger.issue_card(dec!(1000.00)).unwrap();
+        ledger.activate_card().unwrap();
+        for (merchant, amount) in [("Burguer King", dec!(20.00)), ("Habbib's", dec!(30.00))] {
+            ledger.advance_clock(Duration::days(1));
+            ledger
+                .process_purchase(merchant.to_string(), amount, None, Channel::InPerson)
+                .unwrap();
+        }
+
+        let limit = BookAccount::AssetCurrentLimit;
+        let balance_at = |at| ledger.balance_at(&limit, at).unwrap();
+        assert_eq!(balance_at(issued_at - Duration::seconds(1)), dec!(0.00));
+        assert_eq!(balance_at(issued_at), dec!(-1000.00));
+        assert_eq!(balance_at(issued_at + Duration::hours(30)), dec!(-980.00));
+        assert_eq!(balance_at(ledger.now()), dec!(-950.00));
+
+        let lines: Vec<_> = ledger
+            .statement(&limit)
+            .unwrap()
+            .into_iter()
+            .map(|line| (line.amount, line.balance, line.version, line.merchant))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                (dec!(-1000.00), dec!(-1000.00), 1, None),
+                (
+                    dec!(20.00),
+                    dec!(-980.00),
+                    2,
+                    Some("Burguer King".to_string())
+                ),
+                (dec!(30.00), dec!(-950.00), 3, Some("Habbib's".to_string())),
+            ]
+        );
+        assert_eq!(ledger.accounts()[&limit].version(), 3);
+    }
+
+    #[test]
+    fn recovers_from_the_latest_snapshot_and_the_tail() {
+        let mut ledger = Ledger::new()
+            .with_snapshot_interval(4)
+            .with_clock(Clock::Fixed {
+                now: datetime!(2023-03-01 12:00:00 UTC),
+            });
+        ledger.issue_card(dec!(1000.00)).unwrap();
+        ledger.activate_card().unwrap();
+        for (merchant, amount) in [("Burguer King", dec!(20.00)), ("Habbib's", dec!(30.00))] {
+            ledger.advance_clock(Duration::hours(1));
+            ledger
+                .process_purchase(merchant.to_string(), amount, None, Channel::InPerson)
+                .unwrap();
+        }
+        let snapshot = ledger.latest_snapshot().unwrap().clone();
+        assert_eq!(snapshot.offset, 5);
+        assert_eq!(ledger.journal().len(), 8);
+
+        let journal = ledger.journal().to_vec();
+        let recovered = Ledger::recover(snapshot.clone(), journal.clone()).unwrap();
+        assert_eq!(recovered.accounts(), ledger.accounts());
+        assert_eq!(recovered.journal(), ledger.journal());
+        assert_eq!(recovered.latest_snapshot(), Some(&snapshot));
+        let verified = Ledger::recover_verified(snapshot.clone(), journal.clone()).unwrap();
+        assert_eq!(verified.get_balance(), dec!(950.00));
+
+        assert_eq!(
+            Ledger::recover(snapshot.clone(), journal[..3].to_vec()).unwrap_err(),
+            error::Error::SnapshotMismatch {
+                offset: 5,
+                journal_len: 3
+            }
+        );
+        let mut wrong = snapshot;
+        wrong
+            .accounts
+            .get_mut(&BookAccount::AssetCurrentLimit)
+            .unwrap()
+            .amount = dec!(-500.00);
+        assert_eq!(
+            Ledger::recover_verified(wrong, journal).unwrap_err(),
+            error::Error::SnapshotMismatch {
+                offset: 5,
+                journal_len: 8
+            }
+        );
+    }
+
+    #[test]
+    fn latest_snapshot_keeps_what_the_journal_does_not() {
+        let mut ledger = Ledger::new().with_snapshot_interval(2);
+        ledger.issue_card(dec!(1000.00)).unwrap();
+        ledger.activate_card().unwrap();
+        let purchase = Command::Purchase {
+            merchant: "Burguer King".to_string(),
+            amount: dec!(20.00),
+            mcc: None,
+            channel: Channel::InPerson,
+        };
+        let outcome = ledger
+            .execute("p-1", purchase.clone(), Audit::system())
+            .unwrap();
+        ledger.block_card("lost".to_string()).unwrap();
+
+        let snapshot = ledger.latest_snapshot().unwrap().clone();
+        let mut recovered = Ledger::recover(snapshot, ledger.journal().to_vec()).unwrap();
+        assert_eq!(recovered.card().status(), &CardStatus::Blocked);
+        assert_eq!(
+            recovered.execute("p-1", purchase, Audit::system()),
+            Ok(outcome)
+        );
+        assert_eq!(recovered.journal().len(), ledger.journal().len());
+    }
+
+    #[test]
+    fn card_lifecycle_transitions() {
+        let mut ledger = Ledger::new();
+        assert!(matches!(
+            ledger.activate_card(),
+            Err(error::Error::CardNotIssued)
+        ));
+        ledger.issue_card(dec!(1000.00)).unwrap();
+        ledger.activate_card().unwrap();
+        assert!(matches!(
+            ledger.activate_card(),
+            Err(error::Error::CardAlreadyActive)
+        ));
+
+        ledger.block_card("suspected fraud".to_string()).unwrap();
+        assert!(matches!(
+            ledger.process_purchase(
+                "Burguer King".to_string(),
+                dec!(20.00),
+                None,
+                Channel::InPerson
+            ),
+            Err(error::Error::CardBlocked)
+        ));
+        ledger.unblock_card("false alarm".to_string()).unwrap();
+
+        ledger.cancel_card("account closed".to_string()).unwrap();
+        assert!(matches!(
+            ledger.process_purchase(
+                "Burguer King".to_string(),
+                dec!(20.00),
+                None,
+                Channel::InPerson
+            ),
+            Err(error::Error::CardCancelled)
+        ));
+        assert!(matches!(
+            ledger.unblock_card("too late".to_string()),
+            Err(error::Error::CardCancelled)
+        ));
+
+        let statuses: Vec<_> = ledger
+            .card()
+            .status_history()
+            .iter()
+            .map(|change| change.status.clone())
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                CardStatus::Inactive,
+                CardStatus::Active,
+                CardStatus::Blocked,
+                CardStatus::Active,
+                CardStatus::Cancelled,
+            ]
+        );
+    }
+
+    #[test]
+    fn retried_commands_post_once() {
+        let mut ledger = Ledger::new();
+        let issue = Command::IssueCard {
+            max_limit: dec!(1000.00),
+        };
+        let purchase = Command::Purchase {
+            merchant: "Burguer King".to_string(),
+            amount: dec!(20.00),
+            mcc: None,
+            channel: Channel::InPerson,
+        };
+        let cardholder = Audit::new(Actor::Cardholder, "api", "");
+        ledger
+            .execute("issue-1", issue.clone(), Audit::system())
+            .unwrap();
+        ledger
+            .execute("activate-1", Command::ActivateCard, cardholder.clone())
+            .unwrap();
+        ledger
+            .execute("purchase-1", purchase.clone(), cardholder.clone())
+            .unwrap();
+        let journal_len = ledger.journal().len();
+
+        assert_eq!(
+            ledger.execute("issue-1", issue, Audit::system()),
+            Ok(Outcome::CardStatus(CardStatus::Inactive))
+        );
+        assert!(matches!(
+            ledger.execute("purchase-1", purchase, cardholder.clone()),
+            Ok(Outcome::Authorized(_))
+        ));
+        assert_eq!(ledger.journal().len(), journal_len);
+        assert_eq!(ledger.get_balance(), dec!(980.00));
+
+        assert_eq!(
+            ledger.execute(
+                "purchase-1",
+                Command::Payment {
+                    amount: dec!(20.00)
+                },
+                cardholder
+            ),
+            Err(error::Error::IdempotencyKeyReused {
+                key: "purchase-1".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn commands_are_audited_and_hash_chained() {
+        let mut ledger = Ledger::new().with_clock(Clock::Fixed {
+            now: datetime!(2023-03-01 12:00:00 UTC),
+        });
+        let operator = Audit::new(Actor::Operator, "backoffice", "new customer");
+        let cardholder = Audit::new(Actor::Cardholder, "api", "");
+        ledger
+            .run(
+                Command::IssueCard {
+                    max_limit: dec!(1000.00),
+                },
+                operator.clone(),
+            )
+            .unwrap();
+        ledger.advance_clock(Duration::minutes(1));
+        ledger.activate_card().unwrap();
+        ledger.advance_clock(Duration::minutes(1));
+        ledger
+            .run(
+                Command::Purchase {
+                    merchant: "Burguer King".to_string(),
+                    amount: dec!(20.00),
+                    mcc: None,
+                    channel: Channel::InPerson,
+                },
+                cardholder.clone(),
+            )
+            .unwrap();
+        ledger.advance_clock(Duration::minutes(1));
+        let chargeback = Audit::new(Actor::Operator, "backoffice", "chargeback");
+        ledger
+            .run(
+                Command::BlockCard {
+                    reason: "disputed".to_string(),
+                },
+                chargeback.clone(),
+            )
+            .unwrap();
+        ledger.advance_clock(Duration::minutes(1));
+        ledger
+            .run(
+                Command::SetSpendingControls {
+                    controls: SpendingControls::default(),
+                },
+                cardholder,
+            )
+            .unwrap();
+        ledger.advance_clock(Duration::minutes(1));
+        ledger.close_bill().unwrap();
+
+        let trail: Vec<(Actor, String)> = ledger
+            .audit_trail(None)
+            .iter()
+            .map(|record| {
+                let action = serde_json::to_value(record).unwrap()["action"].clone();
+                (
+                    record.audit.actor.clone(),
+                    action.as_str().unwrap().to_string(),
+                )
+            })
+            .collect();
+        let expected = [
+            (Actor::Operator, "posted"),
+            (Actor::Operator, "card_status_changed"),
+            (Actor::System, "card_status_changed"),
+            (Actor::Cardholder, "posted"),
+            (Actor::Operator, "card_status_changed"),
+            (Actor::Cardholder, "spending_controls_updated"),
+            (Actor::System, "posted"),
+        ]
+        .map(|(actor, action)| (actor, action.to_string()));
+        assert_eq!(trail, expected);
+
+        let by_operator = ledger.audit_trail(Some(&Actor::Operator));
+        assert_eq!(by_operator[0].audit, operator);
+        assert_eq!(by_operator[2].audit, chargeback);
+        assert_eq!(
+            by_operator[0].action,
+            Action::Posted {
+                movement_id: ledger.journal()[1].id,
+                hash: ledger.journal()[1].hash.clone()
+            }
+        );
+        assert_eq!(audit::verify(ledger.journal(), Some(0)), Ok(()));
+
+        let mut journal = ledger.journal().to_vec();
+        journal[2].amount = dec!(2.00);
+        assert_eq!(
+            audit::verify(&journal, Some(0)),
+            Err(error::Error::JournalTampered { offset: 2 })
+        );
+        assert_eq!(
+            Ledger::replay(ledger.card().clone(), journal).unwrap_err(),
+            error::Error::JournalTampered { offset: 2 }
+        );
+
+        // dropping hashes doesn't hide an edit
+        let mut journal = ledger.journal().to_vec();
+        let last = journal.len() - 1;
+        journal[last].amount = dec!(0.00);
+        journal[last].hash = None;
+        assert_eq!(
+            audit::verify(&journal, Some(0)),
+            Err(error::Error::JournalTampered { offset: last })
+        );
+        journal.iter_mut().for_each(|entry| entry.hash = None);
+        assert_eq!(
+            Ledger::replay(ledger.card().clone(), journal.clone()).unwrap_err(),
+            error::Error::JournalTampered { offset: 0 }
+        );
+
+        // journals from before auditing have neither, but the snapshot
+        // remembers the chain started
+        journal.iter_mut().for_each(|entry| entry.audit = None);
+        assert_eq!(audit::verify(&journal, None), Ok(()));
+        let snapshot = ledger.snapshot();
+        assert_eq!(snapshot.chained_since, Some(0));
+        assert_eq!(
+            Ledger::recover_verified(snapshot, journal).unwrap_err(),
+            error::Error::JournalTampered { offset: 0 }
+        );
+    }
+
+    #[test]
+    fn anomalous_purchase_is_declined_above_threshold() {
+        let mut ledger = Ledger::new()
+            .with_clock(Clock::Fixed {
+                now: datetime!(2023-03-01 12:00:00 UTC),
+            })
+            .with_scoring(ScoringConfig {
+                flag_threshold: dec!(0.50),
+                decline_threshold: Some(dec!(0.70)),
+            });
+        ledger.issue_card(dec!(1000.00)).unwrap();
+        ledger.activate_card().unwrap();
+        for amount in [
+            dec!(20.00),
+            dec!(21.00),
+            dec!(22.00),
+            dec!(20.00),
+            dec!(21.00),
+        ] {
+            ledger.advance_clock(Duration::days(1));
+            ledger
+                .process_purchase("Burguer King".to_string(), amount, None, Channel::InPerson)
+                .unwrap();
+        }
+
+        ledger.advance_clock(Duration::days(1));
+        assert!(matches!(
+            ledger.process_purchase("Casino".to_string(), dec!(500.00), None, Channel::InPerson),
+            Err(error::Error::AnomalousPurchase { .. })
+        ));
+        let score = ledger
+            .process_purchase("Casino".to_string(), dec!(21.00), None, Channel::InPerson)
+            .unwrap();
+        assert!(!score.flagged);
+        assert_eq!(score.contributions[0].factor, scoring::Factor::NewMerchant);
+    }
+
+    fn active_ledger(max_limit: Decimal) -> Ledger {
+        let mut ledger = Ledger::new();
+        ledger.issue_card(max_limit).unwrap();
+        ledger.activate_card().unwrap();
+        ledger
+    }
+
+    #[test]
+    fn purchases_must_be_positive() {
+        let mut ledger = active_ledger(dec!(100.00));
+        for amount in [dec!(0.00), dec!(-50.00)] {
+            assert_eq!(
+                ledger.process_purchase(
+                    "Burguer King".to_string(),
+                    amount,
+                    None,
+                    Channel::InPerson
+                ),
+                Err(error::Error::InvalidAmount { amount })
+            );
+        }
+        assert_eq!(ledger.get_balance(), dec!(100.00));
+    }
+
+    #[test]
+    fn payments_must_be_positive() {
+        let mut ledger = active_ledger(dec!(100.00));
+        for amount in [dec!(0.00), dec!(-30.00)] {
+            assert_eq!(
+                ledger.process_payment(amount),
+                Err(error::Error::InvalidAmount { amount })
+            );
+        }
+        assert_eq!(ledger.get_balance(), dec!(100.00));
+        assert_eq!(ledger.journal().len(), 2);
+    }
+
+    #[test]
+    fn refunds_must_give_back_a_positive_amount() {
+        let mut ledger = active_ledger(dec!(100.00));
+        // only a journal written before purchases were checked can hold one
+        let entries = movement::purchase("Burguer King".to_string(), dec!(0.00), ledger.now());
+        let purchase_id = entries[0].id;
+        ledger.post(entries).unwrap();
+
+        assert_eq!(
+            ledger.process_refund(purchase_id),
+            Err(error::Error::InvalidAmount { amount: dec!(0.00) })
+        );
+        assert_eq!(ledger.get_balance(), dec!(100.00));
+    }
+
+    #[test]
+    fn ledger_with_subscribers_moves_between_threads() {
+        let mut ledger = Ledger::new();
+        let events = InMemorySink::default();
+        ledger.subscribe(events.clone());
+
+        std::thread::spawn(move || ledger.issue_card(dec!(100.00)).unwrap())
+            .join()
+            .unwrap();
+        assert_eq!(events.events().len(), 1);
+    }
+
+    #[test]
+    fn commands_publish_events() {
+        let mut ledger = Ledger::new();
+        let events = InMemorySink::default();
+        ledger.subscribe(events.clone());
+
+        ledger.issue_card(dec!(100.00)).unwrap();
+        ledger.activate_card().unwrap();
+        ledger
+            .process_purchase(
+                "Burguer King".to_string(),
+                dec!(20.00),
+                None,
+                Channel::InPerson,
+            )
+            .unwrap();
+        ledger
+            .process_purchase(
+                "Habbib's".to_string(),
+                dec!(120.00),
+                None,
+                Channel::InPerson,
+            )
+            .unwrap_err();
+        ledger.close_bill().unwrap();
+        ledger.process_payment(dec!(20.00)).unwrap();
+        ledger.close_bill().unwrap();
+
+        let names: Vec<String> = events
+            .events()
+            .iter()
+            .map(|event| serde_json::to_value(event).unwrap()["event"].to_string())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "\"card_issued\"",
+                "\"card_status_changed\"",
+                "\"purchase_authorized\"",
+                "\"purchase_declined\"",
+                "\"bill_closed\"",
+                "\"payment_received\"",
+                "\"bill_closed\"",
+            ]
+        );
+        match &events.events()[3] {
+            Event::PurchaseDeclined { violation, .. } => {
+                assert_eq!(violation.code(), "insufficient_limit")
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn rewards_points_are_earned_refunded_and_redeemed() {
+        let mut ledger = Ledger::new().with_rewards(RewardsProgram {
+            mcc_multipliers: BTreeMap::from([(5814, dec!(3))]),
+            ..RewardsProgram::default()
+        });
+        ledger.issue_card(dec!(1000.00)).unwrap();
+        ledger.activate_card().unwrap();
+        ledger
+            .process_purchase(
+                "Burguer King".to_string(),
+                dec!(20.50),
+                Some(5814),
+                Channel::InPerson,
+            )
+            .unwrap();
+        ledger
+            .process_purchase("Habbib's".to_string(), dec!(200.00), None, Channel::Online)
+            .unwrap();
+        assert_eq!(ledger.points_balance(), dec!(261));
+
+        let habbibs = ledger
+            .journal()
+            .iter()
+            .rev()
+            .find(|e| e.merchant.as_deref() == Some("Habbib's"))
+            .unwrap()
+            .id;
+        ledger.process_refund(habbibs).unwrap();
+        assert_eq!(ledger.points_balance(), dec!(61));
+        assert_eq!(ledger.get_balance(), dec!(979.50));
+        assert_eq!(
+            ledger.process_refund(habbibs),
+            Err(error::Error::PurchaseAlreadyRefunded { id: habbibs })
+        );
+
+        ledger.close_bill().unwrap();
+        assert_eq!(
+            ledger.redeem_points(dec!(100)),
+            Err(error::Error::InsufficientPoints {
+                requested: dec!(100),
+                available: dec!(61)
+            })
+        );
+        assert_eq!(ledger.redeem_points(dec!(61)), Ok(dec!(0.61)));
+        assert_eq!(ledger.points_balance(), dec!(0));
+        assert_eq!(ledger.get_balance(), dec!(980.11));
+        assert_eq!(
+            ledger.accounts()[&BookAccount::LiabilityReceivable].amount(),
+            dec!(-19.89)
+        );
+        assert_eq!(
+            ledger.accounts()[&BookAccount::EquityRewards].amount(),
+            dec!(-0.61)
+        );
+    }
+
+    #[test]
+    fn refunds_after_billing_and_redemption_are_not_charged_again() {
+        let mut ledger = Ledger::new()
+            .with_clock(Clock::Fixed {
+                now: datetime!(2023-03-01 12:00:00 UTC),
+            })
+            .with_rewards(RewardsProgram::default());
+        ledger.issue_card(dec!(1000.00)).unwrap();
+        ledger.activate_card().unwrap();
+        ledger
+            .set_spending_controls(SpendingControls {
+                daily_cap: Some(dec!(150.00)),
+                ..SpendingControls::default()
+            })
+            .unwrap();
+        ledger.advance_clock(Duration::minutes(5));
+        ledger
+            .process_purchase(
+                "Burguer King".to_string(),
+                dec!(100.00),
+                None,
+                Channel::InPerson,
+            )
+            .unwrap();
+        let purchase_id = ledger.journal().last().unwrap().reference.unwrap();
+
+        ledger.close_bill().unwrap();
+        assert_eq!(ledger.redeem_points(dec!(100)), Ok(dec!(1.00)));
+        ledger.advance_clock(Duration::minutes(5));
+        ledger.process_refund(purchase_id).unwrap();
+        assert_eq!(ledger.points_balance(), dec!(0));
+        assert_eq!(
+            ledger.accounts()[&BookAccount::AssetRewardsPoints].amount(),
+            dec!(0)
+        );
+
+        ledger.close_bill().unwrap();
+        let amount = |account| ledger.accounts()[&account].amount();
+        assert_eq!(amount(BookAccount::AssetSettled), dec!(0.00));
+        assert_eq!(amount(BookAccount::LiabilityReceivable), dec!(1.00));
+        assert_eq!(ledger.get_balance(), dec!(1001.00));
+
+        // the refunded purchase no longer counts towards the daily cap
+        ledger.advance_clock(Duration::minutes(5));
+        ledger
+            .process_purchase(
+                "Habbib's".to_string(),
+                dec!(120.00),
+                None,
+                Channel::InPerson,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn only_positive_whole_points_are_redeemed() {
+        let mut ledger = Ledger::new().with_rewards(RewardsProgram::default());
+        ledger.issue_card(dec!(1000.00)).unwrap();
+        ledger.activate_card().unwrap();
+        ledger
+            .process_purchase(
+                "Burguer King".to_string(),
+                dec!(100.00),
+                None,
+                Channel::InPerson,
+            )
+            .unwrap();
+        ledger.close_bill().unwrap();
+
+        for points in [dec!(-500), dec!(0), dec!(0.5)] {
+            assert_eq!(
+                ledger.redeem_points(points),
+                Err(error::Error::InvalidPoints { points })
+            );
+        }
+        assert_eq!(ledger.points_balance(), dec!(100));
+        assert_eq!(ledger.get_balance(), dec!(900.00));
+    }
+
+    #[test]
+    fn replaced_card_keeps_balance() {
+        let mut ledger = Ledger::new();
+        ledger.issue_card(dec!(1000.00)).unwrap();
+        ledger.activate_card().unwrap();
+        ledger
+            .process_purchase(
+                "Burguer King".to_string(),
+                dec!(20.00),
+                None,
+                Channel::InPerson,
+            )
+            .unwrap();
+        let old_id = ledger.card().id();
+
+        ledger.replace_card("card lost".to_string()).unwrap();
+        assert_eq!(ledger.card().status(), &CardStatus::Inactive);
+        assert_ne!(ledger.card().id(), old_id);
+        assert_eq!(ledger.replaced_cards()[0].status(), &CardStatus::Replaced);
+        assert_eq!(ledger.get_balance(), dec!(980.00));
+    }
+}
@@ -0,0 +1,47 @@
+//! Double-entry ledger for a single credit card account.
+//!
+//! A [`Ledger`] holds the card, the book accounts and the journal of entries
+//! posted to them. Every operation on the account (issuing the card,
+//! purchases, bills, payments) is a [`movement`] made of entries sharing an
+//! id, each one debiting one book account and crediting another by the same
+//! amount, so the book accounts always add up to zero.
+//!
+//! ```
+//! use authorizer::controls::Channel;
+//! use authorizer::Ledger;
+//! use rust_decimal_macros::dec;
+//!
+//! let mut ledger = Ledger::new();
+//! ledger.issue_card(dec!(1000.00)).unwrap();
+//! ledger.activate_card().unwrap();
+//! ledger
+//!     .process_purchase("Burguer King".to_string(), dec!(20.00), None, Channel::InPerson)
+//!     .unwrap();
+//! assert_eq!(ledger.get_balance(), dec!(980.00));
+//! ```
+//!
+//! Commands are rejected with an [`Error`] whose `code` is stable and meant
+//! for clients to match on.
+
+pub mod audit;
+pub mod cash;
+pub mod clock;
+pub mod command;
+pub mod controls;
+pub mod error;
+pub mod events;
+pub mod export;
+pub mod ledger;
+pub mod movement;
+pub mod payout;
+pub mod rewards;
+pub mod rules;
+pub mod scoring;
+pub mod server;
+pub mod settlement;
+pub mod simulation;
+pub mod snapshot;
+pub mod storage;
+
+pub use error::Error;
+pub use ledger::Ledger;
@@ -1,22 +1,84 @@
-use rust_decimal_macros::dec;
+use authorizer::{error, export, server, simulation, storage};
 
-mod error;
-mod ledger;
-mod movement;
+// Usage:
+// cargo run -- serve 127.0.0.1:8080 [events.jsonl] [memory | file:<dir> | sqlite:<path>]
+// cargo run -- export <csv|hledger|gl> journal.json [output]
+// cargo run -- simulate config.json
+//
+// `cargo run --example demo` walks a card through its whole life.
 
-fn main() -> Result<(), error::Result> {
-    let mut ledger = ledger::Ledger::new();
-    ledger.issue_card(dec!(1000.00))?;
-    ledger.activate_card()?;
-    ledger.process_purchase("Burguer King".to_string(), dec!(20.00))?;
-    ledger.close_bill()?;
-    ledger.process_payment(dec!(20.00))?;
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let result = match args.get(1).map(String::as_str) {
+        Some("serve") => {
+            let addr = args.get(2).map_or("127.0.0.1:8080", String::as_str);
+            let events = args.get(3).map(std::path::Path::new);
+            let served = storage::open(args.get(4).map_or("memory", String::as_str))
+                .map_err(Into::into)
+                .and_then(|storage| server::serve(addr, events, storage));
+            if let Err(err) = served {
+                eprintln!("server error: {}", err);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some("export") => export(&args[2..]),
+        Some("simulate") => simulate(&args[2..]),
+        _ => {
+            eprintln!("usage: authorizer <serve|export|simulate> ...");
+            std::process::exit(2);
+        }
+    };
 
-    println!(
-        "card: {:#?}, journal: {:#?}, book accounts: {:#?}",
-        ledger.get_balance(),
-        ledger.journal,
-        ledger.accounts
-    );
+    if let Err(err) = result {
+        println!("{}", serde_json::to_string(&err).unwrap());
+        std::process::exit(1);
+    }
+}
+
+// simulate config.json, see `simulation.rs` and `simulations/`
+fn simulate(args: &[String]) -> Result<(), error::Error> {
+    let invalid = |reason: String| error::Error::SimulationConfigInvalid { reason };
+    let path = args
+        .first()
+        .ok_or_else(|| invalid("usage: simulate config.json".to_string()))?;
+    let json = std::fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+    let config: simulation::SimulationConfig =
+        serde_json::from_str(&json).map_err(|err| invalid(err.to_string()))?;
+    let report = simulation::run(&config)?;
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
     Ok(())
 }
+
+// export <csv|hledger|gl> journal.json [output]
+fn export(args: &[String]) -> Result<(), error::Error> {
+    let (format, journal_path) = match args {
+        [format, journal_path, ..] => (format.as_str(), journal_path),
+        _ => {
+            return Err(error::Error::ExportFailed {
+                reason: "usage: export <csv|hledger|gl> journal.json [output]".to_string(),
+            })
+        }
+    };
+    let journal = export::read_journal(std::path::Path::new(journal_path))?;
+    let out = match format {
+        "csv" => export::csv(&journal),
+        "hledger" => export::hledger(&journal),
+        "gl" => export::general_ledger_csv(&journal),
+        _ => {
+            return Err(error::Error::ExportFailed {
+                reason: format!("unknown format {}", format),
+            })
+        }
+    };
+
+    match args.get(2) {
+        Some(output) => std::fs::write(output, out).map_err(|err| error::Error::ExportFailed {
+            reason: err.to_string(),
+        }),
+        None => {
+            print!("{}", out);
+            Ok(())
+        }
+    }
+}
@@ -1,14 +1,15 @@
+//! Movements: the entries each operation posts to the journal.
 
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
 use time::OffsetDateTime;
 use uuid::Uuid;
-use crate::ledger::{Entry, BookAccount};
+
+use crate::ledger::{BookAccount, Entry};
 
 const INTERCHANGE_FEE: Decimal = dec!(0.02);
 
-pub fn card_issued(max_limit: Decimal) -> Vec<Entry> {
-    let now = OffsetDateTime::now_utc();
+pub fn card_issued(max_limit: Decimal, now: OffsetDateTime) -> Vec<Entry> {
     let id = Uuid::new_v4();
 
     vec![
@@ -19,6 +20,9 @@ pub fn card_issued(max_limit: Decimal) -> Vec<Entry> {
             amount: max_limit,
             post_date: now,
             merchant: None,
+            reference: None,
+            audit: None,
+            hash: None,
         },
         Entry {
             id,
@@ -27,12 +31,16 @@ pub fn card_issued(max_limit: Decimal) -> Vec<Entry> {
             amount: max_limit,
             post_date: now,
             merchant: None,
+            reference: None,
+            audit: None,
+            hash: None,
         },
     ]
 }
 
-pub fn purchase(merchant: String, amount: Decimal) -> Vec<Entry> {
-    let now = OffsetDateTime::now_utc();
+/// The purchase owed to the merchant net of interchange, and the limit it
+/// takes from the card.
+pub fn purchase(merchant: String, amount: Decimal, now: OffsetDateTime) -> Vec<Entry> {
     let id = Uuid::new_v4();
     let interchange: Decimal = (amount * INTERCHANGE_FEE).round_dp(2);
 
@@ -41,9 +49,12 @@ pub fn purchase(merchant: String, amount: Decimal) -> Vec<Entry> {
             id,
             debit_account: BookAccount::AssetSettled,
             credit_account: BookAccount::LiabilityPayable,
-            amount: amount,
+            amount,
             post_date: now,
             merchant: Some(merchant.to_string()),
+            reference: None,
+            audit: None,
+            hash: None,
         },
         Entry {
             id,
@@ -52,34 +63,219 @@ pub fn purchase(merchant: String, amount: Decimal) -> Vec<Entry> {
             amount: interchange,
             post_date: now,
             merchant: Some(merchant.to_string()),
+            reference: None,
+            audit: None,
+            hash: None,
         },
         Entry {
             id,
             debit_account: BookAccount::LiabilityCurrentLimitCp,
             credit_account: BookAccount::AssetCurrentLimit,
-            amount: amount,
+            amount,
             post_date: now,
             merchant: Some(merchant.to_string()),
+            reference: None,
+            audit: None,
+            hash: None,
+        },
+    ]
+}
+
+/// Undoes a movement: the same entries with debit and credit swapped, each
+/// referencing the movement it reverses.
+pub fn reversal(movement: &[Entry], now: OffsetDateTime) -> Vec<Entry> {
+    let id = Uuid::new_v4();
+
+    movement
+        .iter()
+        .map(|entry| Entry {
+            id,
+            debit_account: entry.credit_account.clone(),
+            credit_account: entry.debit_account.clone(),
+            amount: entry.amount,
+            post_date: now,
+            merchant: entry.merchant.clone(),
+            reference: Some(entry.id),
+            audit: None,
+            hash: None,
+        })
+        .collect()
+}
+
+pub fn points_earned(purchase_id: Uuid, points: Decimal, now: OffsetDateTime) -> Vec<Entry> {
+    let id = Uuid::new_v4();
+
+    vec![Entry {
+        id,
+        debit_account: BookAccount::AssetRewardsPoints,
+        credit_account: BookAccount::LiabilityRewardsPointsCp,
+        amount: points,
+        post_date: now,
+        merchant: None,
+        reference: Some(purchase_id),
+        audit: None,
+        hash: None,
+    }]
+}
+
+/// Points given back for a credit on the bill, which frees limit like a payment.
+pub fn points_redeemed(points: Decimal, credit: Decimal, now: OffsetDateTime) -> Vec<Entry> {
+    let id = Uuid::new_v4();
+
+    vec![
+        Entry {
+            id,
+            debit_account: BookAccount::LiabilityRewardsPointsCp,
+            credit_account: BookAccount::AssetRewardsPoints,
+            amount: points,
+            post_date: now,
+            merchant: None,
+            reference: None,
+            audit: None,
+            hash: None,
+        },
+        Entry {
+            id,
+            debit_account: BookAccount::AssetCurrentLimit,
+            credit_account: BookAccount::LiabilityCurrentLimitCp,
+            amount: credit,
+            post_date: now,
+            merchant: None,
+            reference: None,
+            audit: None,
+            hash: None,
+        },
+        Entry {
+            id,
+            debit_account: BookAccount::EquityRewards,
+            credit_account: BookAccount::LiabilityReceivable,
+            amount: credit,
+            post_date: now,
+            merchant: None,
+            reference: None,
+            audit: None,
+            hash: None,
+        },
+    ]
+}
+
+/// Cash owed to the ATM network, the fee charged upfront, and the limit both
+/// take from the card. The limit entry carries no merchant, so cash is never
+/// mistaken for a purchase.
+pub fn cash_advance(atm: String, amount: Decimal, fee: Decimal, now: OffsetDateTime) -> Vec<Entry> {
+    let id = Uuid::new_v4();
+
+    vec![
+        Entry {
+            id,
+            debit_account: BookAccount::AssetCashAdvance,
+            credit_account: BookAccount::LiabilityPayable,
+            amount,
+            post_date: now,
+            merchant: Some(atm),
+            reference: None,
+            audit: None,
+            hash: None,
+        },
+        Entry {
+            id,
+            debit_account: BookAccount::AssetCashAdvance,
+            credit_account: BookAccount::EquityFees,
+            amount: fee,
+            post_date: now,
+            merchant: None,
+            reference: None,
+            audit: None,
+            hash: None,
+        },
+        Entry {
+            id,
+            debit_account: BookAccount::LiabilityCurrentLimitCp,
+            credit_account: BookAccount::AssetCurrentLimit,
+            amount: amount + fee,
+            post_date: now,
+            merchant: None,
+            reference: None,
+            audit: None,
+            hash: None,
         },
     ]
 }
 
-pub fn closed_bill(closed_amount: Decimal) -> Vec<Entry> {
-    let now = OffsetDateTime::now_utc();
+pub fn interest_charged(interest: Decimal, now: OffsetDateTime) -> Vec<Entry> {
+    let id = Uuid::new_v4();
+
+    vec![
+        Entry {
+            id,
+            debit_account: BookAccount::AssetCashAdvance,
+            credit_account: BookAccount::EquityInterest,
+            amount: interest,
+            post_date: now,
+            merchant: None,
+            reference: None,
+            audit: None,
+            hash: None,
+        },
+        Entry {
+            id,
+            debit_account: BookAccount::LiabilityCurrentLimitCp,
+            credit_account: BookAccount::AssetCurrentLimit,
+            amount: interest,
+            post_date: now,
+            merchant: None,
+            reference: None,
+            audit: None,
+            hash: None,
+        },
+    ]
+}
+
+/// Moves the part of a payment that pays back cash advances off the bill.
+pub fn payment_to_cash_advance(
+    payment_id: Uuid,
+    amount: Decimal,
+    now: OffsetDateTime,
+) -> Vec<Entry> {
     let id = Uuid::new_v4();
 
     vec![Entry {
         id,
         debit_account: BookAccount::LiabilityReceivable,
-        credit_account: BookAccount::AssetSettled,
-        amount: closed_amount,
+        credit_account: BookAccount::AssetCashAdvance,
+        amount,
         post_date: now,
         merchant: None,
+        reference: Some(payment_id),
+        audit: None,
+        hash: None,
     }]
 }
 
-pub fn payment(payment_amount: Decimal) -> Vec<Entry> {
-    let now = OffsetDateTime::now_utc();
+/// Moves what was settled since the last bill onto the bill. A negative
+/// amount, when refunds outweigh purchases, is a credit on the bill.
+pub fn closed_bill(closed_amount: Decimal, now: OffsetDateTime) -> Vec<Entry> {
+    let id = Uuid::new_v4();
+    let (debit_account, credit_account) = if closed_amount < Decimal::ZERO {
+        (BookAccount::AssetSettled, BookAccount::LiabilityReceivable)
+    } else {
+        (BookAccount::LiabilityReceivable, BookAccount::AssetSettled)
+    };
+
+    vec![Entry {
+        id,
+        debit_account,
+        credit_account,
+        amount: closed_amount.abs(),
+        post_date: now,
+        merchant: None,
+        reference: None,
+        audit: None,
+        hash: None,
+    }]
+}
+
+pub fn payment(payment_amount: Decimal, now: OffsetDateTime) -> Vec<Entry> {
     let id = Uuid::new_v4();
 
     vec![
@@ -90,6 +286,9 @@ pub fn payment(payment_amount: Decimal) -> Vec<Entry> {
             amount: payment_amount,
             post_date: now,
             merchant: None,
+            reference: None,
+            audit: None,
+            hash: None,
         },
         Entry {
             id,
@@ -98,6 +297,50 @@ pub fn payment(payment_amount: Decimal) -> Vec<Entry> {
             amount: payment_amount,
             post_date: now,
             merchant: None,
+            reference: None,
+            audit: None,
+            hash: None,
         },
     ]
 }
+
+pub fn bank_settled(payment_id: Uuid, amount: Decimal, settled_on: OffsetDateTime) -> Vec<Entry> {
+    let id = Uuid::new_v4();
+
+    vec![Entry {
+        id,
+        debit_account: BookAccount::AssetBank,
+        credit_account: BookAccount::AssetTransitoryBank,
+        amount,
+        post_date: settled_on,
+        merchant: None,
+        reference: Some(payment_id),
+        audit: None,
+        hash: None,
+    }]
+}
+
+/// One entry per purchase being paid out, all under the same movement, so every
+/// purchase can be traced to the payout that settled it.
+pub fn merchant_payout(
+    merchant: String,
+    purchases: &[(Uuid, Decimal)],
+    now: OffsetDateTime,
+) -> Vec<Entry> {
+    let id = Uuid::new_v4();
+
+    purchases
+        .iter()
+        .map(|(purchase_id, amount)| Entry {
+            id,
+            debit_account: BookAccount::LiabilityPayable,
+            credit_account: BookAccount::AssetBank,
+            amount: *amount,
+            post_date: now,
+            merchant: Some(merchant.to_string()),
+            reference: Some(*purchase_id),
+            audit: None,
+            hash: None,
+        })
+        .collect()
+}
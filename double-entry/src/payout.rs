@@ -0,0 +1,173 @@
+//! Daily merchant payout run.
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::error;
+use crate::ledger::{BookAccount, Entry, Ledger};
+use crate::movement;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PayoutInstruction {
+    pub payout_id: Uuid,
+    pub merchant: String,
+    pub amount: Decimal,
+    pub purchases: Vec<Uuid>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HeldPayout {
+    pub merchant: String,
+    pub purchase: Uuid,
+    pub amount: Decimal,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PayoutBatch {
+    pub instructions: Vec<PayoutInstruction>,
+    pub held: Vec<HeldPayout>, // disputed purchases, paid out once resolved
+}
+
+/// Daily settlement run: pays every merchant what we owe them for purchases
+/// posted before `until`, net of interchange, except for the purchases in
+/// dispute, see [`Ledger::open_dispute`].
+pub fn run(ledger: &mut Ledger, until: OffsetDateTime) -> Result<PayoutBatch, error::Error> {
+    let mut batch = PayoutBatch::default();
+    let mut by_merchant: BTreeMap<String, Vec<(Uuid, Decimal)>> = BTreeMap::new();
+
+    for (purchase, (merchant, amount)) in unpaid_purchases(ledger.journal(), until) {
+        if ledger.disputed().contains(&purchase) {
+            batch.held.push(HeldPayout {
+                merchant,
+                purchase,
+                amount,
+            });
+        } else {
+            by_merchant
+                .entry(merchant)
+                .or_default()
+                .push((purchase, amount));
+        }
+    }
+
+    let now = ledger.now();
+    for (merchant, purchases) in by_merchant {
+        let entries = movement::merchant_payout(merchant.clone(), &purchases, now);
+        let payout_id = entries[0].id;
+        ledger.post(entries)?;
+
+        batch.instructions.push(PayoutInstruction {
+            payout_id,
+            merchant,
+            amount: purchases.iter().map(|(_, amount)| amount).sum(),
+            purchases: purchases
+                .into_iter()
+                .map(|(purchase, _)| purchase)
+                .collect(),
+        });
+    }
+    Ok(batch)
+}
+
+// Net payable per purchase movement (amount credited minus interchange) that
+// no payout has referenced yet.
+fn unpaid_purchases(journal: &[Entry], until: OffsetDateTime) -> BTreeMap<Uuid, (String, Decimal)> {
+    let paid: BTreeSet<Uuid> = journal
+        .iter()
+        .filter(|e| e.debit_account == BookAccount::LiabilityPayable)
+        .filter_map(|e| e.reference)
+        .collect();
+
+    let mut payable: BTreeMap<Uuid, (String, Decimal)> = BTreeMap::new();
+    for entry in journal {
+        if entry.post_date >= until || entry.reference.is_some() || paid.contains(&entry.id) {
+            continue;
+        }
+        let Some(merchant) = &entry.merchant else {
+            continue;
+        };
+        let amount = if entry.credit_account == BookAccount::LiabilityPayable {
+            entry.amount
+        } else if entry.debit_account == BookAccount::LiabilityPayable {
+            -entry.amount
+        } else {
+            continue;
+        };
+        payable
+            .entry(entry.id)
+            .or_insert_with(|| (merchant.clone(), Decimal::ZERO))
+            .1 += amount;
+    }
+    payable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Clock;
+    use crate::controls::Channel;
+    use rust_decimal_macros::dec;
+    use time::Duration;
+    use time_macros::datetime;
+
+    #[test]
+    fn pays_merchants_net_of_interchange_and_holds_disputes() {
+        let mut ledger = Ledger::new().with_clock(Clock::Fixed {
+            now: datetime!(2023-03-01 10:00:00 UTC),
+        });
+        ledger.issue_card(dec!(1000.00)).unwrap();
+        ledger.activate_card().unwrap();
+        for (merchant, amount) in [("Burger King", dec!(20.00)), ("Habbib's", dec!(90.00))] {
+            ledger.advance_clock(Duration::minutes(5));
+            ledger
+                .process_purchase(merchant.to_string(), amount, None, Channel::InPerson)
+                .unwrap();
+        }
+        ledger.advance_clock(Duration::minutes(5));
+        ledger
+            .process_purchase(
+                "Burger King".to_string(),
+                dec!(10.00),
+                None,
+                Channel::InPerson,
+            )
+            .unwrap();
+        let disputed = ledger.journal().last().unwrap().id;
+        ledger.open_dispute(disputed).unwrap();
+
+        ledger.advance_clock(Duration::days(1));
+        let until = ledger.now();
+        let batch = run(&mut ledger, until).unwrap();
+
+        assert_eq!(batch.instructions.len(), 2);
+        assert_eq!(batch.instructions[0].merchant, "Burger King");
+        assert_eq!(batch.instructions[0].amount, dec!(19.60));
+        assert_eq!(batch.instructions[1].amount, dec!(88.20));
+        assert_eq!(batch.held[0].amount, dec!(9.80));
+        assert_eq!(
+            ledger.accounts()[&BookAccount::LiabilityPayable].amount(),
+            dec!(9.80)
+        );
+
+        // the dispute is held until it's resolved
+        let batch = run(&mut ledger, until).unwrap();
+        assert!(batch.instructions.is_empty());
+        assert_eq!(batch.held.len(), 1);
+        assert_eq!(
+            ledger.open_dispute(Uuid::nil()),
+            Err(error::Error::PurchaseNotFound { id: Uuid::nil() })
+        );
+
+        ledger.resolve_dispute(disputed).unwrap();
+        let batch = run(&mut ledger, until).unwrap();
+        assert_eq!(batch.instructions.len(), 1);
+        assert_eq!(batch.instructions[0].amount, dec!(9.80));
+        assert_eq!(
+            ledger.accounts()[&BookAccount::LiabilityPayable].amount(),
+            dec!(0.00)
+        );
+    }
+}
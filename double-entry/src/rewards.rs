@@ -0,0 +1,104 @@
+//! Loyalty program: points earned on purchases and redeemed as statement credits.
+
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use std::collections::BTreeMap;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// A multiplier applied to purchases posted between `starts` and `ends`,
+/// only for merchant category `mcc` when one is given.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Promo {
+    #[serde_as(as = "Rfc3339")]
+    pub starts: OffsetDateTime,
+    #[serde_as(as = "Rfc3339")]
+    pub ends: OffsetDateTime, // exclusive
+    pub mcc: Option<u16>,
+    pub multiplier: Decimal,
+}
+
+/// How many points a purchase earns and what a point is worth when redeemed.
+/// Points are whole numbers, fractions are dropped.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RewardsProgram {
+    pub points_per_unit: Decimal, // points per unit of currency spent
+    pub mcc_multipliers: BTreeMap<u16, Decimal>,
+    pub promos: Vec<Promo>,   // the highest applicable multiplier wins
+    pub point_value: Decimal, // statement credit per point
+}
+
+impl Default for RewardsProgram {
+    fn default() -> Self {
+        RewardsProgram {
+            points_per_unit: dec!(1),
+            mcc_multipliers: BTreeMap::new(),
+            promos: vec![],
+            point_value: dec!(0.01),
+        }
+    }
+}
+
+impl RewardsProgram {
+    pub fn points(&self, amount: Decimal, mcc: Option<u16>, now: OffsetDateTime) -> Decimal {
+        let category = mcc
+            .and_then(|mcc| self.mcc_multipliers.get(&mcc))
+            .copied()
+            .unwrap_or(Decimal::ONE);
+        let promo = self
+            .promos
+            .iter()
+            .filter(|promo| promo.starts <= now && now < promo.ends)
+            .filter(|promo| promo.mcc.is_none() || promo.mcc == mcc)
+            .map(|promo| promo.multiplier)
+            .max()
+            .unwrap_or(Decimal::ONE);
+        (amount * self.points_per_unit * category * promo).floor()
+    }
+
+    pub fn credit_for(&self, points: Decimal) -> Decimal {
+        (points * self.point_value).round_dp(2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time_macros::datetime;
+
+    #[test]
+    fn points_apply_category_and_best_promo() {
+        let program = RewardsProgram {
+            mcc_multipliers: BTreeMap::from([(5814, dec!(2))]),
+            promos: vec![
+                Promo {
+                    starts: datetime!(2023-03-01 00:00:00 UTC),
+                    ends: datetime!(2023-04-01 00:00:00 UTC),
+                    mcc: None,
+                    multiplier: dec!(1.5),
+                },
+                Promo {
+                    starts: datetime!(2023-03-10 00:00:00 UTC),
+                    ends: datetime!(2023-03-11 00:00:00 UTC),
+                    mcc: Some(5411),
+                    multiplier: dec!(3),
+                },
+            ],
+            ..RewardsProgram::default()
+        };
+        let in_march = datetime!(2023-03-10 12:00:00 UTC);
+
+        assert_eq!(program.points(dec!(20.99), None, in_march), dec!(31));
+        assert_eq!(program.points(dec!(20.00), Some(5814), in_march), dec!(60));
+        assert_eq!(program.points(dec!(20.00), Some(5411), in_march), dec!(60));
+        assert_eq!(
+            program.points(dec!(20.00), Some(5814), datetime!(2023-04-01 00:00:00 UTC)),
+            dec!(40)
+        );
+        assert_eq!(program.credit_for(dec!(1234)), dec!(12.34));
+    }
+}
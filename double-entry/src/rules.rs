@@ -0,0 +1,73 @@
+//! Purchase rules that look at recent movements.
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime};
+use uuid::Uuid;
+
+use crate::error;
+use crate::ledger::Entry;
+
+const HIGH_FREQUENCY_INTERVAL: Duration = Duration::minutes(2);
+const HIGH_FREQUENCY_MAX_MOVEMENTS: usize = 3;
+
+/// Everything the purchase rules need to know about the past, so they don't
+/// have to scan the whole journal and can be restored from a snapshot.
+#[serde_as]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleState {
+    last_merchant: Option<String>,
+    last_amount: Option<Decimal>,
+    #[serde_as(as = "Vec<(_, Rfc3339)>")]
+    recent_movements: Vec<(Uuid, OffsetDateTime)>,
+}
+
+impl RuleState {
+    pub fn record(&mut self, entry: &Entry) {
+        // Entries settling or reversing another movement are not card activity.
+        if entry.reference.is_some() {
+            return;
+        }
+        self.last_merchant = entry.merchant.clone();
+        self.last_amount = Some(entry.amount);
+
+        if self.recent_movements.last().map(|(id, _)| *id) != Some(entry.id) {
+            self.recent_movements.push((entry.id, entry.post_date));
+        }
+        let oldest = entry.post_date - HIGH_FREQUENCY_INTERVAL;
+        self.recent_movements
+            .retain(|(_, post_date)| *post_date > oldest);
+    }
+
+    pub fn check_purchase(
+        &self,
+        entries: &[Entry],
+        now: OffsetDateTime,
+    ) -> Result<(), error::Error> {
+        let purchase_entry = entries.last().unwrap();
+        if self.last_amount == Some(purchase_entry.amount)
+            && self.last_merchant == purchase_entry.merchant
+        {
+            return Err(error::Error::DoubleTransaction {
+                merchant: purchase_entry.merchant.clone(),
+                amount: purchase_entry.amount,
+            });
+        }
+
+        let count = self
+            .recent_movements
+            .iter()
+            .filter(|(_, post_date)| *post_date > now - HIGH_FREQUENCY_INTERVAL)
+            .count();
+        if count >= HIGH_FREQUENCY_MAX_MOVEMENTS {
+            return Err(error::Error::HighFrequencySmallInterval {
+                movements: count,
+                interval_seconds: HIGH_FREQUENCY_INTERVAL.whole_seconds(),
+            });
+        }
+
+        Ok(())
+    }
+}
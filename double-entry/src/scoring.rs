@@ -0,0 +1,235 @@
+//! Statistical anomaly scoring of purchases.
+
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use time::OffsetDateTime;
+
+use crate::ledger::{BookAccount, Entry};
+
+const MIN_HISTORY: u64 = 5; // purchases needed before a card's habits mean anything
+
+const AMOUNT_WEIGHT: f64 = 0.5;
+const MERCHANT_WEIGHT: f64 = 0.25;
+const TIME_OF_DAY_WEIGHT: f64 = 0.25;
+
+/// Scores at or above `flag_threshold` are flagged; at or above
+/// `decline_threshold` the purchase is declined.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScoringConfig {
+    pub flag_threshold: Decimal,
+    pub decline_threshold: Option<Decimal>, // None never declines, only flags
+}
+
+impl Default for ScoringConfig {
+    fn default() -> Self {
+        ScoringConfig {
+            flag_threshold: dec!(0.50),
+            decline_threshold: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Factor {
+    UnusualAmount,
+    NewMerchant,
+    UnusualTimeOfDay,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contribution {
+    pub factor: Factor,
+    pub score: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnomalyScore {
+    pub score: Decimal, // 0.00 (usual) to 1.00 (never seen anything like it)
+    pub contributions: Vec<Contribution>,
+    pub flagged: bool,
+}
+
+/// What a card's past purchases look like, kept up to date as entries are
+/// posted so scoring doesn't have to scan the journal.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    purchases: u64,
+    total: Decimal,
+    total_of_squares: Decimal,
+    merchants: BTreeSet<String>,
+    hours: [u64; 24], // purchases made in each hour of the day
+}
+
+impl Profile {
+    pub fn record(&mut self, entry: &Entry) {
+        let is_purchase = entry.debit_account == BookAccount::LiabilityCurrentLimitCp
+            && entry.credit_account == BookAccount::AssetCurrentLimit
+            && entry.merchant.is_some();
+        if !is_purchase {
+            return;
+        }
+        self.purchases += 1;
+        self.total += entry.amount;
+        self.total_of_squares += entry.amount * entry.amount;
+        self.merchants.insert(entry.merchant.clone().unwrap());
+        self.hours[usize::from(entry.post_date.hour())] += 1;
+    }
+
+    // How many standard deviations above the usual amount, mapped to 0..1
+    // between one and four deviations.
+    fn amount(&self, amount: f64) -> f64 {
+        let n = self.purchases as f64;
+        let mean = self.total.to_f64().unwrap_or_default() / n;
+        let mean_of_squares = self.total_of_squares.to_f64().unwrap_or_default() / n;
+        let variance = (mean_of_squares - mean.powi(2)).max(0.0);
+        let deviation = variance.sqrt().max(mean * 0.1).max(0.01);
+        (((amount - mean) / deviation - 1.0) / 3.0).clamp(0.0, 1.0)
+    }
+
+    fn merchant(&self, merchant: &str) -> f64 {
+        if self.merchants.contains(merchant) {
+            0.0
+        } else {
+            1.0
+        }
+    }
+
+    // Share of past purchases within an hour of this one; below 10% is unusual.
+    fn time_of_day(&self, hour: u8) -> f64 {
+        let hour = usize::from(hour);
+        let near: u64 = [hour + 23, hour, hour + 1]
+            .iter()
+            .map(|h| self.hours[h % 24])
+            .sum();
+        let share = near as f64 / self.purchases as f64;
+        (1.0 - share / 0.1).clamp(0.0, 1.0)
+    }
+}
+
+/// Scores a purchase against the card's past purchases. Cards with too short
+/// a history always score zero.
+pub fn score(
+    profile: &Profile,
+    merchant: &str,
+    amount: Decimal,
+    now: OffsetDateTime,
+    config: &ScoringConfig,
+) -> AnomalyScore {
+    if profile.purchases < MIN_HISTORY {
+        return AnomalyScore {
+            score: dec!(0.00),
+            contributions: vec![],
+            flagged: false,
+        };
+    }
+
+    let contributions: Vec<Contribution> = [
+        (
+            Factor::UnusualAmount,
+            AMOUNT_WEIGHT * profile.amount(amount.to_f64().unwrap_or_default()),
+        ),
+        (
+            Factor::NewMerchant,
+            MERCHANT_WEIGHT * profile.merchant(merchant),
+        ),
+        (
+            Factor::UnusualTimeOfDay,
+            TIME_OF_DAY_WEIGHT * profile.time_of_day(now.hour()),
+        ),
+    ]
+    .into_iter()
+    .filter(|(_, score)| *score > 0.0)
+    .map(|(factor, score)| Contribution {
+        factor,
+        score: Decimal::try_from(score).unwrap_or_default().round_dp(2),
+    })
+    .collect();
+
+    let score = contributions.iter().map(|c| c.score).sum::<Decimal>();
+    AnomalyScore {
+        flagged: score >= config.flag_threshold,
+        score,
+        contributions,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::movement;
+    use time::Duration;
+    use time_macros::datetime;
+
+    // Ten days of lunch at Burger King, or the first `days` of them.
+    fn profile(days: i64) -> Profile {
+        let start = datetime!(2023-03-01 12:00:00 UTC);
+        let mut profile = Profile::default();
+        for day in 0..days {
+            let entries = movement::purchase(
+                "Burger King".to_string(),
+                dec!(20.00) + Decimal::from(day % 3),
+                start + Duration::days(day),
+            );
+            entries.iter().for_each(|entry| profile.record(entry));
+        }
+        profile
+    }
+
+    #[test]
+    fn usual_purchase_scores_zero() {
+        let now = datetime!(2023-03-12 12:30:00 UTC);
+        let score = score(
+            &profile(10),
+            "Burger King",
+            dec!(21.00),
+            now,
+            &ScoringConfig::default(),
+        );
+        assert_eq!(score.score, dec!(0.00));
+        assert!(!score.flagged);
+    }
+
+    #[test]
+    fn unusual_purchase_is_flagged_with_factors() {
+        let now = datetime!(2023-03-12 03:00:00 UTC);
+        let score = score(
+            &profile(10),
+            "Casino",
+            dec!(900.00),
+            now,
+            &ScoringConfig::default(),
+        );
+        let factors: Vec<_> = score
+            .contributions
+            .iter()
+            .map(|c| c.factor.clone())
+            .collect();
+        assert_eq!(
+            factors,
+            vec![
+                Factor::UnusualAmount,
+                Factor::NewMerchant,
+                Factor::UnusualTimeOfDay
+            ]
+        );
+        assert_eq!(score.score, dec!(1.00));
+        assert!(score.flagged);
+    }
+
+    #[test]
+    fn short_history_is_not_scored() {
+        let now = datetime!(2023-03-12 03:00:00 UTC);
+        let score = score(
+            &profile(2),
+            "Casino",
+            dec!(900.00),
+            now,
+            &ScoringConfig::default(),
+        );
+        assert_eq!(score.contributions, vec![]);
+    }
+}
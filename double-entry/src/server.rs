@@ -0,0 +1,364 @@
+//! HTTP API over a single ledger, kept in any `Storage`.
+
+use rust_decimal::Decimal;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::path::Path;
+use tiny_http::{Header, Method, Request, Response, Server};
+use uuid::Uuid;
+
+use crate::audit::{Actor, Audit};
+use crate::command::{Command, Outcome};
+use crate::controls::{Channel, SpendingControls};
+use crate::error;
+use crate::events::JsonlFileSink;
+use crate::ledger::{BookAccount, Ledger};
+use crate::payout;
+use crate::settlement;
+use crate::storage::{self, Storage};
+
+// Usage:
+// cargo run -- serve 127.0.0.1:8080 [events.jsonl] [memory | file:<dir> | sqlite:<path>]
+//
+// POST /card                       {"max_limit": "1000.00"}
+// POST /card/activate
+// POST /card/controls              {"daily_cap": "200.00", "blocked_categories": [7995], ...}
+// POST /purchases                  {"merchant": "Burguer King", "amount": "20.00", "mcc": 5814, "channel": "online"}
+// POST /bills/close
+// POST /payments                   {"amount": "20.00"}
+// POST /settlements               bank settlement CSV, see `settlement.rs`
+// POST /payouts                    {"disputed": ["<purchase id>", ...]}
+// POST /disputes                   {"purchase_id": "<purchase id>"}
+// POST /disputes/<purchase id>/resolve
+// GET  /journal
+// GET  /audit[/<actor>]
+// GET  /accounts/<account>/statement
+//
+// POST requests may carry an `Idempotency-Key` header, purchases must. They
+// may also carry `Audit-Actor` (cardholder, the default, or operator) and
+// `Audit-Reason`, recorded with every entry the request posts.
+// Disputes, opened on their own or with a payout run, hold the purchase's
+// payout until they are resolved.
+// When an events file is given, every ledger event is appended to it. The
+// ledger is saved to the storage after every request and loaded back from
+// it on start, so with a file or SQLite storage it survives restarts. If
+// saving fails the request gets a 503 and the server goes on.
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IssueCardRequest {
+    max_limit: Decimal,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PurchaseRequest {
+    merchant: String,
+    amount: Decimal,
+    mcc: Option<u16>,
+    #[serde(default)]
+    channel: Channel,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PaymentRequest {
+    amount: Decimal,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DisputeRequest {
+    purchase_id: Uuid,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PayoutRequest {
+    #[serde(default)]
+    disputed: BTreeSet<Uuid>,
+}
+
+const TENANT: &str = "default";
+
+struct Reply {
+    status: u16,
+    body: Value,
+}
+
+pub fn serve(
+    addr: &str,
+    events: Option<&Path>,
+    mut storage: Box<dyn Storage>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let server = Server::http(addr)?;
+    let mut ledger = storage::load(storage.as_ref(), TENANT)?.unwrap_or_default();
+    if let Some(path) = events {
+        ledger.subscribe(JsonlFileSink::open(path)?);
+    }
+
+    for mut request in server.incoming_requests() {
+        let mut reply = match handle(&mut ledger, &mut request) {
+            Ok(reply) => reply,
+            Err(err) => rejected(err),
+        };
+        // When saving fails the ledger keeps the command anyway: the next save
+        // catches the storage up, and a retry with the same idempotency key
+        // gets the original outcome.
+        if *request.method() != Method::Get {
+            if let Err(err) = storage::save(storage.as_mut(), TENANT, &ledger) {
+                reply = rejected(err);
+            }
+        }
+        let response = Response::from_string(reply.body.to_string())
+            .with_status_code(reply.status)
+            .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+        request.respond(response)?;
+    }
+    Ok(())
+}
+
+fn handle(ledger: &mut Ledger, request: &mut Request) -> Result<Reply, error::Error> {
+    let url = request.url().to_string();
+    let path: Vec<&str> = url.trim_matches('/').split('/').collect();
+
+    match (request.method(), path.as_slice()) {
+        (Method::Post, ["card"]) => {
+            let body: IssueCardRequest = parse_body(request)?;
+            positive(body.max_limit)?;
+            let command = Command::IssueCard {
+                max_limit: body.max_limit,
+            };
+            execute(
+                ledger,
+                idempotency_key(request),
+                audit(request)?,
+                command,
+                201,
+            )
+        }
+        (Method::Post, ["card", "activate"]) => execute(
+            ledger,
+            idempotency_key(request),
+            audit(request)?,
+            Command::ActivateCard,
+            200,
+        ),
+        (Method::Post, ["card", "controls"]) => {
+            let controls: SpendingControls = parse_body(request)?;
+            [
+                controls.per_transaction_max,
+                controls.daily_cap,
+                controls.monthly_cap,
+            ]
+            .into_iter()
+            .flatten()
+            .try_for_each(positive)?;
+            let command = Command::SetSpendingControls { controls };
+            execute(
+                ledger,
+                idempotency_key(request),
+                audit(request)?,
+                command,
+                200,
+            )
+        }
+        (Method::Post, ["purchases"]) => {
+            let key = idempotency_key(request)
+                .ok_or_else(|| invalid("missing Idempotency-Key header".to_string()))?;
+            let body: PurchaseRequest = parse_body(request)?;
+            positive(body.amount)?;
+            let command = Command::Purchase {
+                merchant: body.merchant,
+                amount: body.amount,
+                mcc: body.mcc,
+                channel: body.channel,
+            };
+            execute(ledger, Some(key), audit(request)?, command, 201)
+        }
+        (Method::Post, ["bills", "close"]) => execute(
+            ledger,
+            idempotency_key(request),
+            audit(request)?,
+            Command::CloseBill,
+            200,
+        ),
+        (Method::Post, ["payments"]) => {
+            let body: PaymentRequest = parse_body(request)?;
+            positive(body.amount)?;
+            let command = Command::Payment {
+                amount: body.amount,
+            };
+            execute(
+                ledger,
+                idempotency_key(request),
+                audit(request)?,
+                command,
+                201,
+            )
+        }
+        (Method::Post, ["settlements"]) => {
+            let audit = audit(request)?;
+            let lines = settlement::parse(&read_body(request)?)?;
+            let report = ledger.audited(audit, |ledger| settlement::reconcile(ledger, lines))?;
+            Ok(Reply {
+                status: 200,
+                body: json!(report),
+            })
+        }
+        (Method::Post, ["payouts"]) => {
+            let audit = audit(request)?;
+            let body: PayoutRequest = parse_body(request)?;
+            for purchase_id in body.disputed {
+                ledger.open_dispute(purchase_id)?;
+            }
+            let until = ledger.now();
+            let batch = ledger.audited(audit, |ledger| payout::run(ledger, until))?;
+            Ok(Reply {
+                status: 200,
+                body: json!(batch),
+            })
+        }
+        (Method::Post, ["disputes"]) => {
+            let body: DisputeRequest = parse_body(request)?;
+            ledger.open_dispute(body.purchase_id)?;
+            Ok(Reply {
+                status: 201,
+                body: json!({ "disputed": ledger.disputed() }),
+            })
+        }
+        (Method::Post, ["disputes", purchase_id, "resolve"]) => {
+            let purchase_id = purchase_id
+                .parse()
+                .map_err(|_| invalid(format!("bad purchase id {}", purchase_id)))?;
+            ledger.resolve_dispute(purchase_id)?;
+            Ok(Reply {
+                status: 200,
+                body: json!({ "disputed": ledger.disputed() }),
+            })
+        }
+        (Method::Get, ["journal"]) => Ok(Reply {
+            status: 200,
+            body: json!(ledger.journal()),
+        }),
+        (Method::Get, ["audit"]) => Ok(Reply {
+            status: 200,
+            body: json!(ledger.audit_trail(None)),
+        }),
+        (Method::Get, ["audit", actor]) => Ok(Reply {
+            status: 200,
+            body: json!(ledger.audit_trail(Some(&parse_actor(actor)?))),
+        }),
+        (Method::Get, ["accounts", account, "statement"]) => {
+            let account: BookAccount = serde_json::from_value(json!(account))
+                .map_err(|_| invalid(format!("unknown book account {}", account)))?;
+            Ok(Reply {
+                status: 200,
+                body: json!(ledger.statement(&account)?),
+            })
+        }
+        _ => Ok(Reply {
+            status: 404,
+            body: json!({"code": "not_found", "message": format!("no route for {}", url)}),
+        }),
+    }
+}
+
+fn execute(
+    ledger: &mut Ledger,
+    idempotency_key: Option<String>,
+    audit: Audit,
+    command: Command,
+    status: u16,
+) -> Result<Reply, error::Error> {
+    let outcome = match idempotency_key {
+        Some(key) => ledger.execute(&key, command, audit)?,
+        None => ledger.run(command, audit)?,
+    };
+    let mut reply = summary(ledger, status);
+    match outcome {
+        Outcome::Authorized(score) => reply.body["anomaly"] = json!(score),
+        Outcome::Updated => reply.body["controls"] = json!(ledger.spending_controls()),
+        Outcome::CardStatus(_) | Outcome::Posted => (),
+    }
+    Ok(reply)
+}
+
+fn summary(ledger: &Ledger, status: u16) -> Reply {
+    Reply {
+        status,
+        body: json!({
+            "card_status": ledger.card().status(),
+            "available_limit": ledger.get_balance(),
+        }),
+    }
+}
+
+fn rejected(err: error::Error) -> Reply {
+    let status = match err {
+        error::Error::InvalidRequest { .. } | error::Error::SettlementFileInvalid { .. } => 400,
+        error::Error::StorageFailed { .. } | error::Error::SnapshotUnreadable { .. } => 503,
+        _ => 422,
+    };
+    Reply {
+        status,
+        body: json!(err),
+    }
+}
+
+fn read_body(request: &mut Request) -> Result<String, error::Error> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .read_to_string(&mut body)
+        .map_err(|err| invalid(err.to_string()))?;
+    Ok(body)
+}
+
+fn parse_body<T: DeserializeOwned>(request: &mut Request) -> Result<T, error::Error> {
+    serde_json::from_str(&read_body(request)?).map_err(|err| invalid(err.to_string()))
+}
+
+fn idempotency_key(request: &Request) -> Option<String> {
+    header(request, "Idempotency-Key")
+}
+
+fn header(request: &Request, field: &'static str) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(field))
+        .map(|header| header.value.to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn audit(request: &Request) -> Result<Audit, error::Error> {
+    let actor = match header(request, "Audit-Actor") {
+        Some(actor) => parse_actor(&actor)?,
+        None => Actor::Cardholder,
+    };
+    if actor == Actor::System {
+        return Err(invalid(
+            "the system actor is only for what the ledger posts on its own".to_string(),
+        ));
+    }
+    let reason = header(request, "Audit-Reason").unwrap_or_default();
+    Ok(Audit::new(actor, "api", &reason))
+}
+
+fn parse_actor(actor: &str) -> Result<Actor, error::Error> {
+    serde_json::from_value(json!(actor)).map_err(|_| invalid(format!("unknown actor {}", actor)))
+}
+
+fn positive(amount: Decimal) -> Result<(), error::Error> {
+    if amount <= Decimal::ZERO {
+        return Err(invalid(format!("amount must be positive, got {}", amount)));
+    }
+    Ok(())
+}
+
+fn invalid(reason: String) -> error::Error {
+    error::Error::InvalidRequest { reason }
+}
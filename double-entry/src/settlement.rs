@@ -0,0 +1,224 @@
+//! Reconciliation of bank settlement files.
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+use serde_with::serde_as;
+use std::collections::{BTreeMap, BTreeSet};
+use time::format_description::well_known::Rfc3339;
+use time::{Date, Month, OffsetDateTime, Time};
+use uuid::Uuid;
+
+use crate::error;
+use crate::ledger::{BookAccount, Ledger};
+use crate::movement;
+
+// A bank settlement file is a CSV with a header and one line per payment the
+// bank actually credited us:
+//
+// reference,amount,date
+// 6f0c2d9e-4a2b-4d7a-9a55-1b0c3c1c1f7e,20.00,2023-03-05
+//
+// `reference` is the id of the payment movement in the journal.
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SettlementLine {
+    pub line: usize,
+    pub reference: Uuid,
+    pub amount: Decimal,
+    #[serde_as(as = "Rfc3339")]
+    pub settled_on: OffsetDateTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Mismatch {
+    pub settlement: SettlementLine,
+    pub expected: Decimal,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ReconciliationReport {
+    pub matched: Vec<SettlementLine>,
+    pub unmatched: Vec<SettlementLine>, // no pending payment with that reference
+    pub mismatched: Vec<Mismatch>,
+    pub outstanding_payments: Vec<Uuid>, // payments the bank has not settled yet
+}
+
+const HEADER: [&str; 3] = ["reference", "amount", "date"];
+
+pub fn parse(contents: &str) -> Result<Vec<SettlementLine>, error::Error> {
+    let mut rows = contents.lines().enumerate();
+    let header = rows
+        .next()
+        .map(|(_, header)| header.split(',').map(str::trim));
+    if !header.is_some_and(|header| header.eq(HEADER)) {
+        return Err(error::Error::SettlementFileInvalid {
+            line: 1,
+            reason: format!("expected the header {}", HEADER.join(",")),
+        });
+    }
+
+    let mut lines = vec![];
+    for (index, row) in rows {
+        if row.trim().is_empty() {
+            continue;
+        }
+        let line = index + 1;
+        let invalid = |reason: String| error::Error::SettlementFileInvalid { line, reason };
+
+        let fields: Vec<&str> = row.split(',').map(str::trim).collect();
+        let [reference, amount, date] = fields[..] else {
+            return Err(invalid(format!("expected 3 fields, got {}", fields.len())));
+        };
+        lines.push(SettlementLine {
+            line,
+            reference: reference
+                .parse()
+                .map_err(|_| invalid(format!("bad reference {}", reference)))?,
+            amount: amount
+                .parse()
+                .map_err(|_| invalid(format!("bad amount {}", amount)))?,
+            settled_on: parse_date(date).ok_or_else(|| invalid(format!("bad date {}", date)))?,
+        });
+    }
+    Ok(lines)
+}
+
+/// Matches settlement lines against payments still sitting in
+/// `AssetTransitoryBank` and moves every matched amount to `AssetBank`.
+pub fn reconcile(
+    ledger: &mut Ledger,
+    lines: Vec<SettlementLine>,
+) -> Result<ReconciliationReport, error::Error> {
+    let mut pending = pending_payments(ledger);
+    let mut report = ReconciliationReport::default();
+
+    for settlement in lines {
+        match pending.get(&settlement.reference) {
+            None => report.unmatched.push(settlement),
+            Some(expected) if *expected != settlement.amount => report.mismatched.push(Mismatch {
+                expected: *expected,
+                settlement,
+            }),
+            Some(_) => {
+                let entries = movement::bank_settled(
+                    settlement.reference,
+                    settlement.amount,
+                    settlement.settled_on,
+                );
+                ledger.post(entries)?;
+                pending.remove(&settlement.reference);
+                report.matched.push(settlement);
+            }
+        }
+    }
+
+    report.outstanding_payments = pending.into_keys().collect();
+    Ok(report)
+}
+
+// Payment amounts by movement id, for payments not cleared yet.
+fn pending_payments(ledger: &Ledger) -> BTreeMap<Uuid, Decimal> {
+    let settled: BTreeSet<Uuid> = ledger
+        .journal()
+        .iter()
+        .filter(|e| e.credit_account == BookAccount::AssetTransitoryBank)
+        .filter_map(|e| e.reference)
+        .collect();
+
+    ledger
+        .journal()
+        .iter()
+        .filter(|e| e.debit_account == BookAccount::AssetTransitoryBank)
+        .filter(|e| !settled.contains(&e.id))
+        .map(|e| (e.id, e.amount))
+        .collect()
+}
+
+// Accepts `YYYY-MM-DD`, settled at midnight UTC.
+fn parse_date(date: &str) -> Option<OffsetDateTime> {
+    let mut parts = date.split('-').map(str::parse::<u16>);
+    let (Some(Ok(year)), Some(Ok(month)), Some(Ok(day)), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    let month = Month::try_from(u8::try_from(month).ok()?).ok()?;
+    let date = Date::from_calendar_date(year.into(), month, u8::try_from(day).ok()?).ok()?;
+    Some(date.with_time(Time::MIDNIGHT).assume_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Clock;
+    use rust_decimal_macros::dec;
+    use time_macros::datetime;
+
+    #[test]
+    fn reconcile_matches_clears_and_reports() {
+        let mut ledger = Ledger::new().with_clock(Clock::Fixed {
+            now: datetime!(2023-03-01 10:00:00 UTC),
+        });
+        ledger.issue_card(dec!(1000.00)).unwrap();
+        ledger.process_payment(dec!(20.00)).unwrap();
+        let first = ledger.journal().last().unwrap().id;
+        ledger.process_payment(dec!(30.00)).unwrap();
+        let second = ledger.journal().last().unwrap().id;
+        ledger.process_payment(dec!(40.00)).unwrap();
+        let third = ledger.journal().last().unwrap().id;
+
+        let file = format!(
+            "reference,amount,date\n{},20.00,2023-03-05\n{},31.00,2023-03-05\n{},5.00,2023-03-05\n",
+            first,
+            second,
+            Uuid::nil()
+        );
+        let report = reconcile(&mut ledger, parse(&file).unwrap()).unwrap();
+
+        assert_eq!(report.matched.len(), 1);
+        assert_eq!(report.matched[0].reference, first);
+        assert_eq!(report.mismatched[0].expected, dec!(30.00));
+        assert_eq!(report.unmatched[0].line, 4);
+        assert_eq!(report.outstanding_payments.len(), 2);
+        assert!(report.outstanding_payments.contains(&third));
+        assert_eq!(
+            ledger.accounts()[&BookAccount::AssetBank].amount(),
+            dec!(-20.00)
+        );
+        assert_eq!(
+            ledger.accounts()[&BookAccount::AssetTransitoryBank].amount(),
+            dec!(-70.00)
+        );
+
+        // settling the same line again finds nothing pending
+        let report = reconcile(&mut ledger, parse(&file).unwrap()).unwrap();
+        assert_eq!(report.unmatched.len(), 2);
+    }
+
+    #[test]
+    fn parse_reports_bad_lines() {
+        assert_eq!(
+            parse("reference,amount,date\nnot-a-uuid,20.00,2023-03-05\n"),
+            Err(error::Error::SettlementFileInvalid {
+                line: 2,
+                reason: "bad reference not-a-uuid".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn parse_needs_the_header() {
+        let missing = Err(error::Error::SettlementFileInvalid {
+            line: 1,
+            reason: "expected the header reference,amount,date".to_string(),
+        });
+        let line = format!("{},20.00,2023-03-05\n", Uuid::nil());
+        assert_eq!(parse(&line), missing);
+        assert_eq!(parse(""), missing);
+        assert_eq!(
+            parse(&format!("reference, amount, date\n{}", line)).unwrap()[0].line,
+            2
+        );
+    }
+}
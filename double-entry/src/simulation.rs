@@ -0,0 +1,446 @@
+//! What-if runs: months of synthetic card activity through a `Ledger`.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Distribution, LogNormal, Poisson};
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use std::collections::BTreeMap;
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime};
+
+use crate::cash::CashAdvanceTerms;
+use crate::clock::Clock;
+use crate::controls::Channel;
+use crate::error;
+use crate::ledger::{BookAccount, Ledger};
+use crate::rewards::RewardsProgram;
+
+const MERCHANTS: usize = 50;
+
+/// The card being priced and the cardholder using it. Runs with the same
+/// config and seed produce the same report.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SimulationConfig {
+    pub max_limit: Decimal,
+    pub months: u32,
+    pub seed: u64,
+    #[serde_as(as = "Rfc3339")]
+    pub starts: OffsetDateTime, // the first bill closes a month later
+    #[serde(default)]
+    pub rewards: Option<RewardsProgram>,
+    #[serde(default)]
+    pub cash_advance_terms: CashAdvanceTerms,
+    pub profile: SpendingProfile,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpendingProfile {
+    pub purchases_per_day: f64, // on average
+    pub amount: AmountDistribution,
+    #[serde(default)]
+    pub mccs: Vec<u16>, // picked at random for each purchase
+    #[serde(default)]
+    pub cash_advances_per_month: f64,
+    #[serde(default)]
+    pub cash_advance_amount: Decimal,
+    #[serde(default)]
+    pub payment: PaymentBehavior,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AmountDistribution {
+    Uniform { min: Decimal, max: Decimal },
+    LogNormal { median: Decimal, sigma: f64 },
+}
+
+/// What the cardholder pays once a bill is due: nothing with probability
+/// `misses`, everything owed with probability `pays_in_full`, otherwise the
+/// minimum.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PaymentBehavior {
+    pub pays_in_full: f64,
+    pub misses: f64,
+    pub minimum_ratio: Decimal, // of everything owed
+    pub due_days: i64,          // after the bill closes
+}
+
+impl Default for PaymentBehavior {
+    fn default() -> Self {
+        PaymentBehavior {
+            pays_in_full: 1.0,
+            misses: 0.0,
+            minimum_ratio: dec!(0.15),
+            due_days: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MonthlyStatement {
+    pub month: u32, // 1 for the first month simulated
+    #[serde(with = "time::serde::rfc3339")]
+    pub closed_at: OffsetDateTime,
+    pub purchases: usize,
+    pub purchase_volume: Decimal,
+    pub declined: BTreeMap<String, usize>, // by violation code
+    pub cash_advances: usize,
+    pub bill: Decimal, // everything owed once the bill closed
+    pub minimum_due: Decimal,
+    pub paid: Decimal,
+    pub interchange: Decimal,
+    pub interest: Decimal,
+    pub fees: Decimal,
+    pub available_limit: Decimal, // when the bill closed
+    pub months_past_due: u32,     // after the bill was due, 0 if the minimum was paid
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SimulationReport {
+    pub statements: Vec<MonthlyStatement>,
+    pub interchange: Decimal,
+    pub interest: Decimal,
+    pub fees: Decimal,
+    pub delinquent_months: usize,
+}
+
+pub fn run(config: &SimulationConfig) -> Result<SimulationReport, error::Error> {
+    let profile = &config.profile;
+    let purchases_per_day = poisson(profile.purchases_per_day)?;
+    let cash_advances_per_day = poisson(profile.cash_advances_per_month / 30.0)?;
+    let amounts = Amounts::new(&profile.amount)?;
+    let payment = &profile.payment;
+    for probability in [payment.pays_in_full, payment.misses] {
+        if !(0.0..=1.0).contains(&probability) {
+            return Err(invalid(format!("{} is not a probability", probability)));
+        }
+    }
+    if profile.cash_advances_per_month > 0.0 && profile.cash_advance_amount <= Decimal::ZERO {
+        return Err(invalid(format!(
+            "cash advances of {} can't be taken",
+            profile.cash_advance_amount
+        )));
+    }
+    // the payment must land before the next bill closes
+    if !(0..28).contains(&payment.due_days) {
+        return Err(invalid(format!(
+            "bills can't be due {} days later",
+            payment.due_days
+        )));
+    }
+
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let mut ledger = Ledger::new()
+        .with_clock(Clock::Fixed { now: config.starts })
+        .with_cash_advance_terms(config.cash_advance_terms.clone());
+    if let Some(rewards) = &config.rewards {
+        ledger = ledger.with_rewards(rewards.clone());
+    }
+    ledger.issue_card(config.max_limit)?;
+    ledger.activate_card()?;
+
+    let mut statements: Vec<MonthlyStatement> = vec![];
+    let mut due = None; // when the last bill closed must be paid
+    for month in 1..=config.months {
+        let closes_at = add_months(config.starts, month)?;
+        let before = equity(&ledger);
+        let mut purchases = 0;
+        let mut purchase_volume = Decimal::ZERO;
+        let mut cash_advances = 0;
+        let mut declined = BTreeMap::new();
+
+        while ledger.now() < closes_at {
+            let day = ledger.now();
+            if due.is_some_and(|due| day >= due) {
+                due = None;
+                pay(&mut ledger, &mut rng, payment, &mut statements)?;
+            }
+
+            let day_purchases = count(&purchases_per_day, &mut rng);
+            let day_cash_advances = count(&cash_advances_per_day, &mut rng);
+            // spread over the waking hours, well apart for the frequency rule
+            let gap = Duration::hours(16) / (day_purchases + day_cash_advances + 1);
+            ledger.advance_clock(Duration::hours(7));
+            for _ in 0..day_purchases {
+                ledger.advance_clock(gap);
+                let amount = amounts.sample(&mut rng);
+                let merchant = format!("merchant-{}", rng.gen_range(0..MERCHANTS));
+                let mcc = (!profile.mccs.is_empty())
+                    .then(|| profile.mccs[rng.gen_range(0..profile.mccs.len())]);
+                match ledger.process_purchase(merchant, amount, mcc, Channel::InPerson) {
+                    Ok(_) => {
+                        purchases += 1;
+                        purchase_volume += amount;
+                    }
+                    Err(err) => *declined.entry(err.code()).or_default() += 1,
+                }
+            }
+            for _ in 0..day_cash_advances {
+                ledger.advance_clock(gap);
+                let atm = format!("atm-{}", rng.gen_range(0..MERCHANTS));
+                match ledger.process_cash_advance(atm, profile.cash_advance_amount) {
+                    Ok(_) => cash_advances += 1,
+                    Err(err) => *declined.entry(err.code()).or_default() += 1,
+                }
+            }
+            ledger.advance_clock(day + Duration::days(1) - ledger.now());
+        }
+
+        ledger.close_bill()?;
+        let after = equity(&ledger);
+        let bill = owed(&ledger);
+        statements.push(MonthlyStatement {
+            month,
+            closed_at: ledger.now(),
+            purchases,
+            purchase_volume,
+            declined,
+            cash_advances,
+            bill,
+            minimum_due: (bill * payment.minimum_ratio).round_dp(2),
+            paid: Decimal::ZERO,
+            interchange: after.0 - before.0,
+            interest: after.1 - before.1,
+            fees: after.2 - before.2,
+            available_limit: ledger.get_balance(),
+            months_past_due: 0,
+        });
+        due = Some(ledger.now() + Duration::days(payment.due_days));
+    }
+    if let Some(due) = due {
+        ledger.advance_clock(due - ledger.now());
+        pay(&mut ledger, &mut rng, payment, &mut statements)?;
+    }
+
+    Ok(SimulationReport {
+        interchange: statements.iter().map(|s| s.interchange).sum(),
+        interest: statements.iter().map(|s| s.interest).sum(),
+        fees: statements.iter().map(|s| s.fees).sum(),
+        delinquent_months: statements.iter().filter(|s| s.months_past_due > 0).count(),
+        statements,
+    })
+}
+
+// Pays the last bill, or not, and records it on its statement. Paying less
+// than the minimum makes the account one more month past due.
+fn pay(
+    ledger: &mut Ledger,
+    rng: &mut StdRng,
+    payment: &PaymentBehavior,
+    statements: &mut [MonthlyStatement],
+) -> Result<(), error::Error> {
+    let past_due = match statements {
+        [.., previous, _] => previous.months_past_due,
+        _ => 0,
+    };
+    let statement = statements.last_mut().unwrap();
+    let roll: f64 = rng.gen();
+    let amount = if roll < payment.misses {
+        Decimal::ZERO
+    } else if roll < payment.misses + payment.pays_in_full {
+        owed(ledger)
+    } else {
+        statement.minimum_due.min(owed(ledger))
+    };
+    if amount > Decimal::ZERO {
+        ledger.process_payment(amount)?;
+    }
+    statement.paid = amount;
+    statement.months_past_due = if amount < statement.minimum_due {
+        past_due + 1
+    } else {
+        0
+    };
+    Ok(())
+}
+
+// Everything the cardholder owes: closed bills and cash advances.
+fn owed(ledger: &Ledger) -> Decimal {
+    -amount(ledger, BookAccount::LiabilityReceivable)
+        - amount(ledger, BookAccount::AssetCashAdvance)
+}
+
+// Interchange, interest and fees earned so far.
+fn equity(ledger: &Ledger) -> (Decimal, Decimal, Decimal) {
+    (
+        amount(ledger, BookAccount::EquityInterchange),
+        amount(ledger, BookAccount::EquityInterest),
+        amount(ledger, BookAccount::EquityFees),
+    )
+}
+
+fn amount(ledger: &Ledger, account: BookAccount) -> Decimal {
+    ledger
+        .accounts()
+        .get(&account)
+        .map_or(Decimal::ZERO, |info| info.amount())
+}
+
+fn add_months(starts: OffsetDateTime, months: u32) -> Result<OffsetDateTime, error::Error> {
+    let month0 = starts.month() as u32 - 1 + months;
+    let year = starts.year() + (month0 / 12) as i32;
+    let month = time::Month::try_from((month0 % 12 + 1) as u8).unwrap();
+    // a bill closing on the 31st closes on the last day of shorter months
+    let day = starts
+        .day()
+        .min(time::util::days_in_year_month(year, month));
+    let date =
+        time::Date::from_calendar_date(year, month, day).map_err(|err| invalid(err.to_string()))?;
+    Ok(starts.replace_date(date))
+}
+
+fn poisson(mean: f64) -> Result<Option<Poisson<f64>>, error::Error> {
+    if mean == 0.0 {
+        return Ok(None);
+    }
+    Poisson::new(mean)
+        .map(Some)
+        .map_err(|err| invalid(format!("average {}: {}", mean, err)))
+}
+
+enum Amounts {
+    Uniform(Decimal, Decimal),
+    LogNormal(LogNormal<f64>),
+}
+
+impl Amounts {
+    fn new(distribution: &AmountDistribution) -> Result<Self, error::Error> {
+        match distribution {
+            AmountDistribution::Uniform { min, max } if min <= max && *min > Decimal::ZERO => {
+                Ok(Amounts::Uniform(*min, *max))
+            }
+            AmountDistribution::Uniform { min, max } => {
+                Err(invalid(format!("no amounts between {} and {}", min, max)))
+            }
+            AmountDistribution::LogNormal { median, sigma } => {
+                LogNormal::new(median.to_f64().unwrap_or_default().ln(), *sigma)
+                    .map(Amounts::LogNormal)
+                    .map_err(|err| invalid(format!("log-normal amounts: {}", err)))
+            }
+        }
+    }
+
+    fn sample(&self, rng: &mut StdRng) -> Decimal {
+        let cents = match self {
+            Amounts::Uniform(min, max) => {
+                let cents = |amount: &Decimal| (amount * dec!(100)).to_i64().unwrap_or(1);
+                rng.gen_range(cents(min)..=cents(max))
+            }
+            Amounts::LogNormal(distribution) => (distribution.sample(rng) * 100.0).round() as i64,
+        };
+        Decimal::new(cents.max(1), 2)
+    }
+}
+
+fn count(distribution: &Option<Poisson<f64>>, rng: &mut StdRng) -> i32 {
+    distribution
+        .as_ref()
+        .map_or(0, |distribution| distribution.sample(rng) as i32)
+}
+
+fn invalid(reason: String) -> error::Error {
+    error::Error::SimulationConfigInvalid { reason }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time_macros::datetime;
+
+    fn config(payment: PaymentBehavior) -> SimulationConfig {
+        SimulationConfig {
+            max_limit: dec!(2000.00),
+            months: 3,
+            seed: 7,
+            starts: datetime!(2023-01-01 00:00:00 UTC),
+            rewards: None,
+            cash_advance_terms: CashAdvanceTerms::default(),
+            profile: SpendingProfile {
+                purchases_per_day: 1.0,
+                amount: AmountDistribution::Uniform {
+                    min: dec!(5.00),
+                    max: dec!(40.00),
+                },
+                mccs: vec![],
+                cash_advances_per_month: 0.0,
+                cash_advance_amount: Decimal::ZERO,
+                payment,
+            },
+        }
+    }
+
+    #[test]
+    fn same_seed_same_report() {
+        let report = run(&config(PaymentBehavior::default())).unwrap();
+        assert_eq!(run(&config(PaymentBehavior::default())).unwrap(), report);
+
+        assert_eq!(report.statements.len(), 3);
+        assert_eq!(
+            report.statements[1].closed_at,
+            datetime!(2023-03-01 00:00:00 UTC)
+        );
+        for statement in &report.statements {
+            assert_eq!(statement.paid, statement.bill);
+            // 2% of each purchase, rounded to the cent
+            let rounding = Decimal::from(statement.purchases) * dec!(0.005);
+            assert!(
+                (statement.interchange - statement.purchase_volume * dec!(0.02)).abs() <= rounding
+            );
+        }
+        assert_eq!(report.delinquent_months, 0);
+    }
+
+    #[test]
+    fn missed_payments_add_up_months_past_due() {
+        let report = run(&config(PaymentBehavior {
+            misses: 1.0,
+            ..PaymentBehavior::default()
+        }))
+        .unwrap();
+
+        let past_due: Vec<u32> = report
+            .statements
+            .iter()
+            .map(|s| s.months_past_due)
+            .collect();
+        assert_eq!(past_due, [1, 2, 3]);
+        assert!(report.statements.iter().all(|s| s.paid == Decimal::ZERO));
+        assert!(report.statements[2].bill > report.statements[0].bill);
+    }
+
+    #[test]
+    fn cash_advances_need_a_positive_amount() {
+        let mut config = config(PaymentBehavior::default());
+        config.profile.cash_advances_per_month = 2.0;
+        config.profile.cash_advance_amount = dec!(-100.00);
+        assert_eq!(
+            run(&config).unwrap_err().code(),
+            "simulation_config_invalid"
+        );
+    }
+
+    #[test]
+    fn months_keep_the_starting_day() {
+        let starts = datetime!(2024-01-31 09:00:00 UTC);
+        assert_eq!(
+            add_months(starts, 1).unwrap(),
+            datetime!(2024-02-29 09:00:00 UTC)
+        );
+        assert_eq!(
+            add_months(starts, 2).unwrap(),
+            datetime!(2024-03-31 09:00:00 UTC)
+        );
+        assert_eq!(
+            add_months(starts, 13).unwrap(),
+            datetime!(2025-02-28 09:00:00 UTC)
+        );
+    }
+}
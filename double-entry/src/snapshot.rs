@@ -0,0 +1,55 @@
+//! Snapshots of the ledger state, to recover without replaying the whole journal.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+use uuid::Uuid;
+
+use crate::command::Processed;
+use crate::controls::{ControlsChange, MonthSpending, SpendingControls};
+use crate::error;
+use crate::ledger::{AccountInfo, BookAccount, Card};
+use crate::rules::RuleState;
+use crate::scoring::Profile;
+
+/// Ledger state as of `offset` journal entries.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub offset: usize, // number of journal entries already applied
+    pub card: Card,
+    pub replaced_cards: Vec<Card>,
+    pub accounts: BTreeMap<BookAccount, AccountInfo>,
+    pub rules: RuleState,
+    #[serde(default)]
+    pub controls: SpendingControls,
+    #[serde(default)]
+    pub controls_history: Vec<ControlsChange>,
+    #[serde(default)]
+    pub profile: Profile,
+    #[serde(default)]
+    pub spending: MonthSpending,
+    pub processed: BTreeMap<String, Processed>,
+    #[serde(default)]
+    pub disputed: BTreeSet<Uuid>,
+    #[serde(default)]
+    pub chained_since: Option<usize>, // offset of the first hashed entry
+}
+
+impl Snapshot {
+    pub fn save(&self, path: &Path) -> Result<(), error::Error> {
+        let json = serde_json::to_string(self).map_err(unreadable)?;
+        fs::write(path, json).map_err(unreadable)
+    }
+
+    pub fn load(path: &Path) -> Result<Snapshot, error::Error> {
+        let json = fs::read_to_string(path).map_err(unreadable)?;
+        serde_json::from_str(&json).map_err(unreadable)
+    }
+}
+
+fn unreadable(err: impl std::fmt::Display) -> error::Error {
+    error::Error::SnapshotUnreadable {
+        reason: err.to_string(),
+    }
+}
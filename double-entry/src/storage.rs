@@ -0,0 +1,407 @@
+//! Where ledgers live between commands: in memory, in files or in SQLite.
+
+use rusqlite::{params, Connection, OptionalExtension};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use time::format_description::well_known::Rfc3339;
+
+use crate::error;
+use crate::ledger::{Entry, Ledger};
+use crate::snapshot::Snapshot;
+
+/// The journal and latest snapshot of many ledgers, one per tenant. The
+/// journal is append-only; the snapshot carries everything the journal
+/// doesn't, like the card and the idempotency keys.
+pub trait Storage {
+    fn tenants(&self) -> Result<Vec<String>, error::Error>;
+    fn journal(&self, tenant: &str) -> Result<Vec<Entry>, error::Error>;
+    fn journal_len(&self, tenant: &str) -> Result<usize, error::Error>;
+    fn append(&mut self, tenant: &str, entries: &[Entry]) -> Result<(), error::Error>;
+    fn snapshot(&self, tenant: &str) -> Result<Option<Snapshot>, error::Error>;
+    fn save_snapshot(&mut self, tenant: &str, snapshot: &Snapshot) -> Result<(), error::Error>;
+}
+
+/// Appends the entries the storage doesn't have yet and replaces the snapshot.
+pub fn save(storage: &mut dyn Storage, tenant: &str, ledger: &Ledger) -> Result<(), error::Error> {
+    let stored = storage.journal_len(tenant)?;
+    if stored > ledger.journal().len() {
+        return Err(error::Error::SnapshotMismatch {
+            offset: stored,
+            journal_len: ledger.journal().len(),
+        });
+    }
+    storage.append(tenant, &ledger.journal()[stored..])?;
+    storage.save_snapshot(tenant, &ledger.snapshot())
+}
+
+/// The tenant's ledger as last saved, or `None` for a tenant never saved.
+/// Configuration such as the clock is not stored and starts at its default.
+pub fn load(storage: &dyn Storage, tenant: &str) -> Result<Option<Ledger>, error::Error> {
+    match storage.snapshot(tenant)? {
+        Some(snapshot) => Ledger::recover(snapshot, storage.journal(tenant)?).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Opens the storage described by `spec`: `memory`, `file:<dir>` or
+/// `sqlite:<path>`.
+pub fn open(spec: &str) -> Result<Box<dyn Storage>, error::Error> {
+    match spec.split_once(':') {
+        None if spec == "memory" => Ok(Box::<InMemoryStorage>::default()),
+        Some(("file", dir)) => Ok(Box::new(FileStorage::open(Path::new(dir))?)),
+        Some(("sqlite", path)) => Ok(Box::new(SqliteStorage::open(Path::new(path))?)),
+        _ => Err(error::Error::StorageFailed {
+            reason: format!("unknown storage {:?}", spec),
+        }),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct InMemoryStorage {
+    tenants: BTreeMap<String, (Vec<Entry>, Option<Snapshot>)>,
+}
+
+impl Storage for InMemoryStorage {
+    fn tenants(&self) -> Result<Vec<String>, error::Error> {
+        Ok(self.tenants.keys().cloned().collect())
+    }
+
+    fn journal(&self, tenant: &str) -> Result<Vec<Entry>, error::Error> {
+        Ok(self
+            .tenants
+            .get(tenant)
+            .map(|(journal, _)| journal.clone())
+            .unwrap_or_default())
+    }
+
+    fn journal_len(&self, tenant: &str) -> Result<usize, error::Error> {
+        Ok(self
+            .tenants
+            .get(tenant)
+            .map_or(0, |(journal, _)| journal.len()))
+    }
+
+    fn append(&mut self, tenant: &str, entries: &[Entry]) -> Result<(), error::Error> {
+        let (journal, _) = self.tenants.entry(tenant.to_string()).or_default();
+        journal.extend_from_slice(entries);
+        Ok(())
+    }
+
+    fn snapshot(&self, tenant: &str) -> Result<Option<Snapshot>, error::Error> {
+        Ok(self
+            .tenants
+            .get(tenant)
+            .and_then(|(_, snapshot)| snapshot.clone()))
+    }
+
+    fn save_snapshot(&mut self, tenant: &str, snapshot: &Snapshot) -> Result<(), error::Error> {
+        self.tenants.entry(tenant.to_string()).or_default().1 = Some(snapshot.clone());
+        Ok(())
+    }
+}
+
+/// One directory per tenant, holding `journal.jsonl` with one entry per line
+/// and `snapshot.json`.
+#[derive(Debug)]
+pub struct FileStorage {
+    dir: PathBuf,
+    journal_lens: RefCell<BTreeMap<String, usize>>, // counted once, then kept up to date
+}
+
+impl FileStorage {
+    pub fn open(dir: &Path) -> Result<Self, error::Error> {
+        fs::create_dir_all(dir).map_err(failed)?;
+        Ok(FileStorage {
+            dir: dir.to_path_buf(),
+            journal_lens: RefCell::default(),
+        })
+    }
+
+    fn tenant_dir(&self, tenant: &str) -> Result<PathBuf, error::Error> {
+        if tenant.is_empty()
+            || !tenant
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(error::Error::StorageFailed {
+                reason: format!("invalid tenant name {:?}", tenant),
+            });
+        }
+        Ok(self.dir.join(tenant))
+    }
+}
+
+impl Storage for FileStorage {
+    fn tenants(&self) -> Result<Vec<String>, error::Error> {
+        let mut tenants = vec![];
+        for dir in fs::read_dir(&self.dir).map_err(failed)? {
+            let dir = dir.map_err(failed)?;
+            if dir.file_type().map_err(failed)?.is_dir() {
+                tenants.push(dir.file_name().to_string_lossy().to_string());
+            }
+        }
+        tenants.sort();
+        Ok(tenants)
+    }
+
+    fn journal(&self, tenant: &str) -> Result<Vec<Entry>, error::Error> {
+        let path = self.tenant_dir(tenant)?.join("journal.jsonl");
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        fs::read_to_string(path)
+            .map_err(failed)?
+            .lines()
+            .map(|line| serde_json::from_str(line).map_err(failed))
+            .collect()
+    }
+
+    fn journal_len(&self, tenant: &str) -> Result<usize, error::Error> {
+        if let Some(len) = self.journal_lens.borrow().get(tenant) {
+            return Ok(*len);
+        }
+        let path = self.tenant_dir(tenant)?.join("journal.jsonl");
+        let len = match File::open(path) {
+            Ok(file) => BufReader::new(file).lines().count(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(failed(err)),
+        };
+        self.journal_lens
+            .borrow_mut()
+            .insert(tenant.to_string(), len);
+        Ok(len)
+    }
+
+    fn append(&mut self, tenant: &str, entries: &[Entry]) -> Result<(), error::Error> {
+        let len = self.journal_len(tenant)?;
+        let dir = self.tenant_dir(tenant)?;
+        fs::create_dir_all(&dir).map_err(failed)?;
+        let mut lines = String::new();
+        for entry in entries {
+            lines.push_str(&serde_json::to_string(entry).map_err(failed)?);
+            lines.push('\n');
+        }
+        // Forgotten until counted again, in case only part of it was written.
+        self.journal_lens.borrow_mut().remove(tenant);
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join("journal.jsonl"))
+            .and_then(|mut file| file.write_all(lines.as_bytes()))
+            .map_err(failed)?;
+        self.journal_lens
+            .borrow_mut()
+            .insert(tenant.to_string(), len + entries.len());
+        Ok(())
+    }
+
+    fn snapshot(&self, tenant: &str) -> Result<Option<Snapshot>, error::Error> {
+        let path = self.tenant_dir(tenant)?.join("snapshot.json");
+        if !path.exists() {
+            return Ok(None);
+        }
+        Snapshot::load(&path).map(Some)
+    }
+
+    // Written next to the old one and renamed over it, so a crash never
+    // leaves half a snapshot behind.
+    fn save_snapshot(&mut self, tenant: &str, snapshot: &Snapshot) -> Result<(), error::Error> {
+        let dir = self.tenant_dir(tenant)?;
+        fs::create_dir_all(&dir).map_err(failed)?;
+        let partial = dir.join("snapshot.json.partial");
+        snapshot.save(&partial)?;
+        fs::rename(partial, dir.join("snapshot.json")).map_err(failed)
+    }
+}
+
+/// Entries are stored as JSON, keyed by tenant and position in the journal.
+pub struct SqliteStorage {
+    connection: Connection,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &Path) -> Result<Self, error::Error> {
+        SqliteStorage::with_connection(Connection::open(path).map_err(failed)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self, error::Error> {
+        SqliteStorage::with_connection(Connection::open_in_memory().map_err(failed)?)
+    }
+
+    fn with_connection(connection: Connection) -> Result<Self, error::Error> {
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS entries (
+                    tenant TEXT NOT NULL,
+                    position INTEGER NOT NULL,
+                    id TEXT NOT NULL,
+                    post_date TEXT NOT NULL,
+                    entry TEXT NOT NULL,
+                    PRIMARY KEY (tenant, position)
+                );
+                CREATE TABLE IF NOT EXISTS snapshots (
+                    tenant TEXT PRIMARY KEY,
+                    snapshot TEXT NOT NULL
+                );",
+            )
+            .map_err(failed)?;
+        Ok(SqliteStorage { connection })
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn tenants(&self) -> Result<Vec<String>, error::Error> {
+        let mut statement = self
+            .connection
+            .prepare(
+                "SELECT tenant FROM entries UNION SELECT tenant FROM snapshots ORDER BY tenant",
+            )
+            .map_err(failed)?;
+        let tenants = statement
+            .query_map([], |row| row.get(0))
+            .map_err(failed)?
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(failed)?;
+        Ok(tenants)
+    }
+
+    fn journal(&self, tenant: &str) -> Result<Vec<Entry>, error::Error> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT entry FROM entries WHERE tenant = ?1 ORDER BY position")
+            .map_err(failed)?;
+        let rows = statement
+            .query_map([tenant], |row| row.get::<_, String>(0))
+            .map_err(failed)?;
+        let mut journal = vec![];
+        for row in rows {
+            journal.push(serde_json::from_str(&row.map_err(failed)?).map_err(failed)?);
+        }
+        Ok(journal)
+    }
+
+    fn journal_len(&self, tenant: &str) -> Result<usize, error::Error> {
+        self.connection
+            .query_row(
+                "SELECT COUNT(*) FROM entries WHERE tenant = ?1",
+                [tenant],
+                |row| row.get(0),
+            )
+            .map_err(failed)
+    }
+
+    fn append(&mut self, tenant: &str, entries: &[Entry]) -> Result<(), error::Error> {
+        let offset = self.journal_len(tenant)?;
+        let transaction = self.connection.transaction().map_err(failed)?;
+        for (position, entry) in (offset..).zip(entries) {
+            transaction
+                .execute(
+                    "INSERT INTO entries (tenant, position, id, post_date, entry)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        tenant,
+                        position,
+                        entry.id.to_string(),
+                        entry.post_date.format(&Rfc3339).map_err(failed)?,
+                        serde_json::to_string(entry).map_err(failed)?,
+                    ],
+                )
+                .map_err(failed)?;
+        }
+        transaction.commit().map_err(failed)
+    }
+
+    fn snapshot(&self, tenant: &str) -> Result<Option<Snapshot>, error::Error> {
+        let json: Option<String> = self
+            .connection
+            .query_row(
+                "SELECT snapshot FROM snapshots WHERE tenant = ?1",
+                [tenant],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(failed)?;
+        json.map(|json| serde_json::from_str(&json).map_err(failed))
+            .transpose()
+    }
+
+    fn save_snapshot(&mut self, tenant: &str, snapshot: &Snapshot) -> Result<(), error::Error> {
+        let json = serde_json::to_string(snapshot).map_err(failed)?;
+        self.connection
+            .execute(
+                "INSERT INTO snapshots (tenant, snapshot) VALUES (?1, ?2)
+                 ON CONFLICT (tenant) DO UPDATE SET snapshot = excluded.snapshot",
+                params![tenant, json],
+            )
+            .map_err(failed)?;
+        Ok(())
+    }
+}
+
+fn failed(err: impl std::fmt::Display) -> error::Error {
+    error::Error::StorageFailed {
+        reason: err.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controls::Channel;
+    use rust_decimal_macros::dec;
+    use uuid::Uuid;
+
+    // Two tenants, saved after every command, must come back as they were.
+    fn round_trip(storage: &mut dyn Storage) {
+        let mut alice = Ledger::new();
+        let mut bob = Ledger::new();
+        alice.issue_card(dec!(1000.00)).unwrap();
+        save(storage, "alice", &alice).unwrap();
+        bob.issue_card(dec!(500.00)).unwrap();
+        save(storage, "bob", &bob).unwrap();
+        alice.activate_card().unwrap();
+        alice
+            .process_purchase(
+                "Burguer King".to_string(),
+                dec!(20.00),
+                None,
+                Channel::InPerson,
+            )
+            .unwrap();
+        save(storage, "alice", &alice).unwrap();
+
+        assert_eq!(storage.tenants().unwrap(), ["alice", "bob"]);
+        assert_eq!(storage.journal_len("alice").unwrap(), 5);
+
+        let loaded = load(storage, "alice").unwrap().unwrap();
+        assert_eq!(loaded.journal(), alice.journal());
+        assert_eq!(loaded.accounts(), alice.accounts());
+        assert_eq!(loaded.card(), alice.card());
+        assert_eq!(loaded.get_balance(), dec!(980.00));
+        assert_eq!(
+            load(storage, "bob").unwrap().unwrap().get_balance(),
+            dec!(500.00)
+        );
+        assert!(load(storage, "carol").unwrap().is_none());
+    }
+
+    #[test]
+    fn in_memory_round_trip() {
+        round_trip(&mut InMemoryStorage::default());
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let dir = std::env::temp_dir().join(format!("ledger-storage-{}", Uuid::new_v4()));
+        round_trip(&mut FileStorage::open(&dir).unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sqlite_round_trip() {
+        round_trip(&mut SqliteStorage::open_in_memory().unwrap());
+    }
+}
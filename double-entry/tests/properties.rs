@@ -0,0 +1,78 @@
+use proptest::prelude::*;
+use rust_decimal::Decimal;
+use time::Duration;
+use time_macros::datetime;
+
+use authorizer::clock::Clock;
+use authorizer::controls::Channel;
+use authorizer::ledger::{BookAccount, Ledger};
+
+#[derive(Debug, Clone)]
+enum Op {
+    Purchase { merchant: usize, amount: Decimal },
+    Wait { minutes: i64 },
+    CloseBill,
+    Payment { amount: Decimal },
+}
+
+const MERCHANTS: [&str; 3] = ["Burger King", "Habbib's", "McDonald's"];
+
+fn cents(max: i64) -> impl Strategy<Value = Decimal> {
+    (1..=max).prop_map(|cents| Decimal::new(cents, 2))
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => (0..MERCHANTS.len(), cents(200_000))
+            .prop_map(|(merchant, amount)| Op::Purchase { merchant, amount }),
+        3 => (0..10i64).prop_map(|minutes| Op::Wait { minutes }),
+        1 => Just(Op::CloseBill),
+        2 => cents(200_000).prop_map(|amount| Op::Payment { amount }),
+    ]
+}
+
+fn amount(ledger: &Ledger, account: BookAccount) -> Decimal {
+    ledger.accounts()[&account].amount()
+}
+
+proptest! {
+    #[test]
+    fn ledger_invariants(max_limit in cents(500_000), ops in prop::collection::vec(op(), 0..60)) {
+        let mut ledger = Ledger::new().with_clock(Clock::Fixed {
+            now: datetime!(2023-03-01 10:00:00 UTC),
+        });
+        ledger.issue_card(max_limit).unwrap();
+        ledger.activate_card().unwrap();
+
+        for op in ops {
+            let available = ledger.get_balance();
+            match op {
+                Op::Purchase { merchant, amount } => {
+                    let result = ledger.process_purchase(MERCHANTS[merchant].to_string(), amount, None, Channel::InPerson);
+                    if result.is_ok() {
+                        prop_assert!(amount <= available);
+                    }
+                }
+                Op::Wait { minutes } => ledger.advance_clock(Duration::minutes(minutes)),
+                Op::CloseBill => ledger.close_bill().unwrap(),
+                Op::Payment { amount: wanted } => {
+                    // pay at most what was billed, like a real cardholder would
+                    let billed = -amount(&ledger, BookAccount::LiabilityReceivable);
+                    let paid = wanted.min(billed);
+                    if paid > Decimal::ZERO {
+                        ledger.process_payment(paid).unwrap();
+                    }
+                }
+            }
+
+            // available limit never goes below zero nor above the card limit
+            let current_limit = -amount(&ledger, BookAccount::AssetCurrentLimit);
+            prop_assert!(current_limit >= Decimal::ZERO);
+            prop_assert!(current_limit <= max_limit);
+
+            // every entry debits and credits the same amount
+            let total: Decimal = ledger.accounts().values().map(|info| info.amount()).sum();
+            prop_assert_eq!(total, Decimal::ZERO);
+        }
+    }
+}
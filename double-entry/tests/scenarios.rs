@@ -0,0 +1,114 @@
+// Runs every `tests/scenarios/*.jsonl` file against a fresh ledger. Each line
+// is one step:
+//
+// {"run": {"command": "purchase", "merchant": "Burger King", "amount": "20.00"}}
+// {"run": {"command": "purchase", ...}, "violation": "insufficient_limit"}
+// {"run": {"command": "block_card", ...}, "audit": {"actor": "operator", "channel": "backoffice", "reason": "chargeback"}}
+// {"advance_minutes": 5}
+// {"expect": {"available_limit": "980.00", "balances": {"asset_settled": "-20.00"}}}
+//
+// The ledger clock is fixed at 2023-03-01T10:00:00Z and only moves with
+// `advance_minutes`.
+
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use time::Duration;
+use time_macros::datetime;
+
+use authorizer::audit::Audit;
+use authorizer::clock::Clock;
+use authorizer::command::Command;
+use authorizer::ledger::{BookAccount, CardStatus, Ledger};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Step {
+    run: Option<Command>,
+    violation: Option<String>,
+    #[serde(default)]
+    audit: Audit, // the system's when not given
+    advance_minutes: Option<i64>,
+    expect: Option<Expect>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Expect {
+    available_limit: Option<Decimal>,
+    card_status: Option<CardStatus>,
+    journal_len: Option<usize>,
+    #[serde(default)]
+    balances: BTreeMap<BookAccount, Decimal>,
+}
+
+fn run_scenario(path: &Path) {
+    let mut ledger = Ledger::new().with_clock(Clock::Fixed {
+        now: datetime!(2023-03-01 10:00:00 UTC),
+    });
+
+    let contents = fs::read_to_string(path).unwrap();
+    for (number, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let at = format!("{}:{}", path.display(), number + 1);
+        let step: Step = serde_json::from_str(line).unwrap_or_else(|err| panic!("{}: {}", at, err));
+
+        if let Some(minutes) = step.advance_minutes {
+            ledger.advance_clock(Duration::minutes(minutes));
+        }
+        if let Some(command) = step.run {
+            let violation = ledger.run(command, step.audit).err().map(|err| err.code());
+            assert_eq!(violation, step.violation, "{}: violation", at);
+        }
+        if let Some(expect) = step.expect {
+            if let Some(available_limit) = expect.available_limit {
+                assert_eq!(
+                    ledger.get_balance(),
+                    available_limit,
+                    "{}: available limit",
+                    at
+                );
+            }
+            if let Some(card_status) = expect.card_status {
+                assert_eq!(ledger.card().status(), &card_status, "{}: card status", at);
+            }
+            if let Some(journal_len) = expect.journal_len {
+                assert_eq!(
+                    ledger.journal().len(),
+                    journal_len,
+                    "{}: journal length",
+                    at
+                );
+            }
+            for (account, amount) in expect.balances {
+                assert_eq!(
+                    ledger.accounts()[&account].amount(),
+                    amount,
+                    "{}: {:?} balance",
+                    at,
+                    account
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn scenarios() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scenarios");
+    let mut paths: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "jsonl"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+
+    for path in paths {
+        run_scenario(&path);
+    }
+}
@@ -0,0 +1,316 @@
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+
+struct TestServer {
+    addr: String,
+    process: Child,
+}
+
+impl TestServer {
+    fn start() -> Self {
+        TestServer::start_with(&[])
+    }
+
+    fn start_with(args: &[&str]) -> Self {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = format!("127.0.0.1:{}", port);
+        let process = Command::new(env!("CARGO_BIN_EXE_authorizer"))
+            .args(["serve", &addr])
+            .args(args)
+            .spawn()
+            .unwrap();
+
+        for _ in 0..50 {
+            if TcpStream::connect(&addr).is_ok() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        TestServer { addr, process }
+    }
+
+    fn request(&self, method: &str, path: &str, headers: &[&str], body: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(&self.addr).unwrap();
+        let mut raw = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+            method,
+            path,
+            self.addr,
+            body.len()
+        );
+        for header in headers {
+            raw.push_str(header);
+            raw.push_str("\r\n");
+        }
+        raw.push_str("\r\n");
+        raw.push_str(body);
+        stream.write_all(raw.as_bytes()).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+#[test]
+fn card_purchase_bill_and_payment() {
+    let server = TestServer::start();
+
+    let (status, body) = server.request("POST", "/card", &[], r#"{"max_limit": "1000.00"}"#);
+    assert_eq!(status, 201);
+    assert_eq!(body["card_status"], json!("inactive"));
+
+    let (status, body) = server.request(
+        "POST",
+        "/purchases",
+        &["Idempotency-Key: p-1"],
+        r#"{"merchant": "Burguer King", "amount": "20.00"}"#,
+    );
+    assert_eq!(status, 422);
+    assert_eq!(body["code"], json!("card_inactive"));
+
+    let (status, _) = server.request("POST", "/card/activate", &[], "");
+    assert_eq!(status, 200);
+
+    let (status, body) = server.request(
+        "POST",
+        "/purchases",
+        &["Idempotency-Key: p-2"],
+        r#"{"merchant": "Burguer King", "amount": "20.00"}"#,
+    );
+    assert_eq!(status, 201);
+    assert_eq!(body["available_limit"], json!("980.00"));
+
+    let (status, _) = server.request("POST", "/bills/close", &[], "");
+    assert_eq!(status, 200);
+    let (status, body) = server.request("POST", "/payments", &[], r#"{"amount": "20.00"}"#);
+    assert_eq!(status, 201);
+    assert_eq!(body["available_limit"], json!("1000.00"));
+
+    let (status, body) = server.request("GET", "/journal", &[], "");
+    assert_eq!(status, 200);
+    assert_eq!(body.as_array().unwrap().len(), 8);
+
+    let (status, body) = server.request("GET", "/accounts/asset_current_limit/statement", &[], "");
+    assert_eq!(status, 200);
+    assert_eq!(
+        body.as_array().unwrap().last().unwrap()["balance"],
+        json!("-1000.00")
+    );
+}
+
+#[test]
+fn purchase_retries_with_same_idempotency_key() {
+    let server = TestServer::start();
+    server.request("POST", "/card", &[], r#"{"max_limit": "100.00"}"#);
+    server.request("POST", "/card/activate", &[], "");
+
+    let purchase = r#"{"merchant": "Habbib's", "amount": "30.00"}"#;
+    let first = server.request("POST", "/purchases", &["Idempotency-Key: p-1"], purchase);
+    let retry = server.request("POST", "/purchases", &["Idempotency-Key: p-1"], purchase);
+    assert_eq!(first, retry);
+    assert_eq!(retry.1["available_limit"], json!("70.00"));
+
+    let (status, body) = server.request(
+        "POST",
+        "/purchases",
+        &["Idempotency-Key: p-1"],
+        r#"{"merchant": "Habbib's", "amount": "40.00"}"#,
+    );
+    assert_eq!(status, 422);
+    assert_eq!(body["code"], json!("idempotency_key_reused"));
+}
+
+#[test]
+fn invalid_requests_are_rejected() {
+    let server = TestServer::start();
+
+    let (status, body) = server.request("POST", "/card", &[], r#"{"max_limit": "-1"}"#);
+    assert_eq!(status, 400);
+    assert_eq!(body["code"], json!("invalid_request"));
+
+    let (status, _) = server.request("POST", "/card", &[], r#"{"limit": "100"}"#);
+    assert_eq!(status, 400);
+
+    let (status, body) = server.request(
+        "POST",
+        "/purchases",
+        &[],
+        r#"{"merchant": "Burguer King", "amount": "20.00"}"#,
+    );
+    assert_eq!(status, 400);
+    assert_eq!(body["reason"], json!("missing Idempotency-Key header"));
+
+    let (status, _) = server.request(
+        "POST",
+        "/card",
+        &["Audit-Actor: system"],
+        r#"{"max_limit": "100.00"}"#,
+    );
+    assert_eq!(status, 400);
+
+    let (status, _) = server.request("GET", "/nowhere", &[], "");
+    assert_eq!(status, 404);
+}
+
+#[test]
+fn audit_trail_says_who_blocked_the_card() {
+    let server = TestServer::start();
+    server.request("POST", "/card", &[], r#"{"max_limit": "100.00"}"#);
+    server.request("POST", "/card/activate", &[], "");
+    server.request(
+        "POST",
+        "/card/controls",
+        &["Audit-Actor: operator", "Audit-Reason: chargeback"],
+        r#"{"online_purchases": false}"#,
+    );
+
+    let (status, body) = server.request("GET", "/audit/operator", &[], "");
+    assert_eq!(status, 200);
+    assert_eq!(body[0]["action"], json!("spending_controls_updated"));
+    assert_eq!(body[0]["reason"], json!("chargeback"));
+    assert_eq!(body[0]["channel"], json!("api"));
+}
+
+#[test]
+fn settlements_are_posted_as_the_request_actor() {
+    let server = TestServer::start();
+    server.request("POST", "/card", &[], r#"{"max_limit": "100.00"}"#);
+    server.request("POST", "/card/activate", &[], "");
+    let purchase = r#"{"merchant": "Habbib's", "amount": "30.00"}"#;
+    server.request("POST", "/purchases", &["Idempotency-Key: p-1"], purchase);
+    server.request("POST", "/bills/close", &[], "");
+    server.request("POST", "/payments", &[], r#"{"amount": "30.00"}"#);
+    let (_, journal) = server.request("GET", "/journal", &[], "");
+    let payment = journal.as_array().unwrap().last().unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let file = format!("reference,amount,date\n{},30.00,2023-03-05\n", payment);
+
+    let (status, _) = server.request("POST", "/settlements", &["Audit-Actor: system"], &file);
+    assert_eq!(status, 400);
+    let (status, _) = server.request("POST", "/payouts", &["Audit-Actor: system"], "{}");
+    assert_eq!(status, 400);
+
+    let (status, body) = server.request("POST", "/settlements", &["Audit-Actor: operator"], &file);
+    assert_eq!(status, 200);
+    assert_eq!(body["matched"].as_array().unwrap().len(), 1);
+    let (_, journal) = server.request("GET", "/journal", &[], "");
+    let settled = journal.as_array().unwrap().last().unwrap();
+    assert_eq!(settled["audit"]["actor"], json!("operator"));
+    assert_eq!(settled["audit"]["channel"], json!("api"));
+}
+
+#[test]
+fn spending_controls_decline_purchases() {
+    let server = TestServer::start();
+    server.request("POST", "/card", &[], r#"{"max_limit": "1000.00"}"#);
+    server.request("POST", "/card/activate", &[], "");
+
+    let (status, body) = server.request(
+        "POST",
+        "/card/controls",
+        &[],
+        r#"{"per_transaction_max": "50.00", "online_purchases": false}"#,
+    );
+    assert_eq!(status, 200);
+    assert_eq!(body["controls"]["per_transaction_max"], json!("50.00"));
+
+    let (status, body) = server.request(
+        "POST",
+        "/purchases",
+        &["Idempotency-Key: p-1"],
+        r#"{"merchant": "Amazon", "amount": "20.00", "mcc": 5942, "channel": "online"}"#,
+    );
+    assert_eq!(status, 422);
+    assert_eq!(body["code"], json!("online_purchases_disabled"));
+
+    let (status, body) = server.request(
+        "POST",
+        "/purchases",
+        &["Idempotency-Key: p-2"],
+        r#"{"merchant": "Burguer King", "amount": "60.00"}"#,
+    );
+    assert_eq!(status, 422);
+    assert_eq!(body["code"], json!("transaction_max_exceeded"));
+
+    let (status, _) = server.request("POST", "/card/controls", &[], r#"{"daily_cap": "0"}"#);
+    assert_eq!(status, 400);
+}
+
+#[test]
+fn ledger_survives_a_restart_with_sqlite_storage() {
+    let dir = std::env::temp_dir().join(format!("server-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let events = dir.join("events.jsonl");
+    let storage = format!("sqlite:{}", dir.join("ledger.db").display());
+    let args = [events.to_str().unwrap(), &storage];
+
+    let server = TestServer::start_with(&args);
+    server.request("POST", "/card", &[], r#"{"max_limit": "100.00"}"#);
+    server.request("POST", "/card/activate", &[], "");
+    let purchase = r#"{"merchant": "Habbib's", "amount": "30.00"}"#;
+    let first = server.request("POST", "/purchases", &["Idempotency-Key: p-1"], purchase);
+    drop(server);
+
+    let server = TestServer::start_with(&args);
+    let retry = server.request("POST", "/purchases", &["Idempotency-Key: p-1"], purchase);
+    assert_eq!(first, retry);
+    let (_, body) = server.request("GET", "/journal", &[], "");
+    assert_eq!(body.as_array().unwrap().len(), 5);
+    drop(server);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn failed_saves_get_a_503_and_are_caught_up_later() {
+    let dir = std::env::temp_dir().join(format!("server-files-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let events = dir.join("events.jsonl");
+    let storage = format!("file:{}", dir.join("ledgers").display());
+    let args = [events.to_str().unwrap(), &storage];
+    let journal = dir.join("ledgers").join("default").join("journal.jsonl");
+    let moved = dir.join("journal.jsonl");
+
+    let server = TestServer::start_with(&args);
+    server.request("POST", "/card", &[], r#"{"max_limit": "100.00"}"#);
+    std::fs::rename(&journal, &moved).unwrap();
+    std::fs::create_dir(&journal).unwrap(); // appending to it fails
+
+    let (status, body) = server.request("POST", "/card/activate", &[], "");
+    assert_eq!(status, 503);
+    assert_eq!(body["code"], json!("storage_failed"));
+    let (status, _) = server.request("GET", "/journal", &[], "");
+    assert_eq!(status, 200);
+
+    std::fs::remove_dir(&journal).unwrap();
+    std::fs::rename(&moved, &journal).unwrap();
+    let purchase = r#"{"merchant": "Habbib's", "amount": "30.00"}"#;
+    let (status, _) = server.request("POST", "/purchases", &["Idempotency-Key: p-1"], purchase);
+    assert_eq!(status, 201);
+    drop(server);
+
+    let server = TestServer::start_with(&args);
+    let (_, body) = server.request("GET", "/journal", &[], "");
+    assert_eq!(body.as_array().unwrap().len(), 5);
+    drop(server);
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,307 @@
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+
+use crate::{Account, AllowList, Input, LimitUpdate, Refund, Transaction};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Violation {
+    AccountAlreadyInitialized,
+    AccountNotInitialized,
+    CardNotActive,
+    InsufficientLimit,
+    CardAlreadyBlocked,
+    CardNotBlocked,
+    InvalidLimit,
+    RefundWithoutTransaction,
+    LimitOverflow,
+}
+
+impl Violation {
+    /// The name the violation is serialized under.
+    pub fn code(&self) -> String {
+        match serde_json::to_value(self) {
+            Ok(Value::String(code)) => code,
+            _ => unreachable!("violations serialize to their name"),
+        }
+    }
+}
+
+/// The account after an operation, and why the operation was rejected if it
+/// was. The account is `{}` until one is created.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Outcome {
+    #[serde(serialize_with = "account_or_empty")]
+    pub account: Option<Account>,
+    pub violations: Vec<Violation>,
+}
+
+fn account_or_empty<S: Serializer>(account: &Option<Account>, s: S) -> Result<S::Ok, S::Error> {
+    match account {
+        Some(account) => account.serialize(s),
+        None => serde_json::Map::new().serialize(s),
+    }
+}
+
+/// Keeps the account across operations. Rejected operations leave it as it was.
+#[derive(Debug, Default)]
+pub struct Authorizer {
+    account: Option<Account>,
+    approved: Vec<Transaction>, // not refunded yet
+}
+
+impl Authorizer {
+    pub fn apply(&mut self, input: Input) -> Outcome {
+        let violations = match input {
+            Input::Account(account) => self.create_account(account),
+            Input::Transaction(transaction) => self.authorize(transaction),
+            Input::Refund(refund) => self.refund(&refund),
+            Input::CardBlock(_) => self.set_card_blocked(true),
+            Input::CardUnblock(_) => self.set_card_blocked(false),
+            Input::LimitUpdate(update) => self.update_limit(&update),
+            Input::AllowListed(allow_list) => self.allow_list(&allow_list),
+        };
+        Outcome {
+            account: self.account.clone(),
+            violations,
+        }
+    }
+
+    fn create_account(&mut self, account: Account) -> Vec<Violation> {
+        if self.account.is_some() {
+            return vec![Violation::AccountAlreadyInitialized];
+        }
+        self.account = Some(account);
+        vec![]
+    }
+
+    fn authorize(&mut self, transaction: Transaction) -> Vec<Violation> {
+        let Some(account) = &mut self.account else {
+            return vec![Violation::AccountNotInitialized];
+        };
+        let mut violations = vec![];
+        if !account.active_card || account.blocked {
+            violations.push(Violation::CardNotActive);
+        }
+        if transaction.amount > account.available_limit {
+            violations.push(Violation::InsufficientLimit);
+        }
+        if violations.is_empty() {
+            account.available_limit -= transaction.amount;
+            self.approved.push(transaction);
+        }
+        violations
+    }
+
+    // Refunds the oldest approved transaction it matches, once. A refund that
+    // would take the limit past what it can hold is rejected.
+    fn refund(&mut self, refund: &Refund) -> Vec<Violation> {
+        let Some(account) = &mut self.account else {
+            return vec![Violation::AccountNotInitialized];
+        };
+        let refunded = self
+            .approved
+            .iter()
+            .position(|t| t.merchant == refund.merchant && t.amount == refund.amount);
+        let Some(index) = refunded else {
+            return vec![Violation::RefundWithoutTransaction];
+        };
+        match account.available_limit.checked_add(refund.amount) {
+            Some(limit) => {
+                self.approved.remove(index);
+                account.available_limit = limit;
+                vec![]
+            }
+            None => vec![Violation::LimitOverflow],
+        }
+    }
+
+    // Blocking is on top of `active-card`: unblocking a card that was never
+    // active leaves it inactive.
+    fn set_card_blocked(&mut self, blocked: bool) -> Vec<Violation> {
+        let Some(account) = &mut self.account else {
+            return vec![Violation::AccountNotInitialized];
+        };
+        match (account.blocked, blocked) {
+            (true, true) => vec![Violation::CardAlreadyBlocked],
+            (false, false) => vec![Violation::CardNotBlocked],
+            _ => {
+                account.blocked = blocked;
+                vec![]
+            }
+        }
+    }
+
+    fn update_limit(&mut self, update: &LimitUpdate) -> Vec<Violation> {
+        let Some(account) = &mut self.account else {
+            return vec![Violation::AccountNotInitialized];
+        };
+        if update.available_limit == 0 {
+            return vec![Violation::InvalidLimit];
+        }
+        account.available_limit = update.available_limit;
+        vec![]
+    }
+
+    fn allow_list(&mut self, allow_list: &AllowList) -> Vec<Violation> {
+        let Some(account) = &mut self.account else {
+            return vec![Violation::AccountNotInitialized];
+        };
+        account.allow_listed = allow_list.active;
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CardBlock, CardUnblock};
+    use serde_json::json;
+    use time_macros::datetime;
+
+    fn transaction(amount: u32) -> Input {
+        Input::Transaction(Transaction {
+            merchant: "Burger King".to_string(),
+            amount,
+            time: datetime!(2019-02-13 10:00:00 UTC),
+        })
+    }
+
+    fn account(active_card: bool, available_limit: u32) -> Input {
+        Input::Account(Account {
+            active_card,
+            available_limit,
+            allow_listed: false,
+            blocked: false,
+        })
+    }
+
+    fn refund(amount: u32) -> Input {
+        Input::Refund(Refund {
+            merchant: "Burger King".to_string(),
+            amount,
+            time: datetime!(2019-02-13 11:00:00 UTC),
+        })
+    }
+
+    #[test]
+    fn transactions_are_checked_against_the_account() {
+        let mut authorizer = Authorizer::default();
+        let outcomes: Vec<_> = [
+            transaction(20),
+            account(true, 100),
+            account(true, 350),
+            transaction(20),
+            transaction(90),
+            transaction(80),
+        ]
+        .into_iter()
+        .map(|input| serde_json::to_value(authorizer.apply(input)).unwrap())
+        .collect();
+
+        assert_eq!(
+            outcomes,
+            [
+                json!({"account": {}, "violations": ["account-not-initialized"]}),
+                json!({"account": {"active-card": true, "available-limit": 100}, "violations": []}),
+                json!({"account": {"active-card": true, "available-limit": 100}, "violations": ["account-already-initialized"]}),
+                json!({"account": {"active-card": true, "available-limit": 80}, "violations": []}),
+                json!({"account": {"active-card": true, "available-limit": 80}, "violations": ["insufficient-limit"]}),
+                json!({"account": {"active-card": true, "available-limit": 0}, "violations": []}),
+            ]
+        );
+    }
+
+    #[test]
+    fn inactive_card_rejects_every_transaction() {
+        let mut authorizer = Authorizer::default();
+        authorizer.apply(account(false, 100));
+
+        let outcome = authorizer.apply(transaction(200));
+        assert_eq!(
+            outcome.violations,
+            [Violation::CardNotActive, Violation::InsufficientLimit]
+        );
+        assert_eq!(outcome.account.unwrap().available_limit, 100);
+    }
+
+    #[test]
+    fn card_refund_and_limit_operations() {
+        let mut authorizer = Authorizer::default();
+        let mut violations = |input| authorizer.apply(input).violations;
+
+        assert_eq!(violations(refund(20)), [Violation::AccountNotInitialized]);
+        assert!(violations(account(true, 100)).is_empty());
+        assert!(violations(transaction(20)).is_empty());
+        assert!(violations(refund(20)).is_empty());
+        assert_eq!(
+            violations(refund(20)),
+            [Violation::RefundWithoutTransaction]
+        );
+        assert_eq!(
+            violations(Input::CardUnblock(CardUnblock {
+                reason: String::new()
+            })),
+            [Violation::CardNotBlocked]
+        );
+        assert!(violations(Input::CardBlock(CardBlock {
+            reason: "lost".to_string()
+        }))
+        .is_empty());
+        assert_eq!(violations(transaction(20)), [Violation::CardNotActive]);
+        assert_eq!(
+            violations(Input::LimitUpdate(LimitUpdate { available_limit: 0 })),
+            [Violation::InvalidLimit]
+        );
+
+        authorizer.apply(Input::LimitUpdate(LimitUpdate {
+            available_limit: 500,
+        }));
+        let outcome = authorizer.apply(Input::AllowListed(AllowList { active: true }));
+        assert_eq!(
+            serde_json::to_value(outcome).unwrap(),
+            json!({"account": {"active-card": true, "available-limit": 500, "allow-listed": true, "blocked": true}, "violations": []})
+        );
+    }
+
+    #[test]
+    fn blocking_is_kept_apart_from_activation() {
+        let mut authorizer = Authorizer::default();
+        let mut violations = |input| authorizer.apply(input).violations;
+        let block = || {
+            Input::CardBlock(CardBlock {
+                reason: "lost".to_string(),
+            })
+        };
+        let unblock = || {
+            Input::CardUnblock(CardUnblock {
+                reason: String::new(),
+            })
+        };
+
+        assert!(violations(account(false, 100)).is_empty());
+        assert_eq!(violations(unblock()), [Violation::CardNotBlocked]);
+        assert!(violations(block()).is_empty());
+        assert_eq!(violations(block()), [Violation::CardAlreadyBlocked]);
+        assert!(violations(unblock()).is_empty());
+
+        let outcome = authorizer.apply(transaction(20));
+        assert_eq!(outcome.violations, [Violation::CardNotActive]);
+        assert!(!outcome.account.as_ref().unwrap().active_card);
+        assert!(!outcome.account.unwrap().blocked);
+    }
+
+    #[test]
+    fn refund_past_the_largest_limit_is_rejected() {
+        let mut authorizer = Authorizer::default();
+        authorizer.apply(account(true, 100));
+        authorizer.apply(transaction(20));
+        authorizer.apply(Input::LimitUpdate(LimitUpdate {
+            available_limit: u32::MAX,
+        }));
+
+        let outcome = authorizer.apply(refund(20));
+        assert_eq!(outcome.violations, [Violation::LimitOverflow]);
+        assert_eq!(outcome.account.unwrap().available_limit, u32::MAX);
+    }
+}
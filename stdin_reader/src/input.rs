@@ -0,0 +1,374 @@
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::fmt;
+use std::io::{self, BufRead, Cursor, Read};
+use std::str::FromStr;
+
+use crate::Input;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Auto,
+    Jsonl,
+    Json, // a single array of operations
+    Csv,
+    MessagePack,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "auto" => Ok(Format::Auto),
+            "jsonl" => Ok(Format::Jsonl),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "msgpack" => Ok(Format::MessagePack),
+            _ => Err(format!("unknown input format {:?}", format)),
+        }
+    }
+}
+
+/// A record that could not be read. `line` is the line in JSON Lines and
+/// CSV, and the position of the operation in a JSON array or MessagePack
+/// stream. `column` is 0 when unknown.
+#[derive(Debug, Serialize)]
+pub struct LineError {
+    line: usize,
+    column: usize,
+    message: String,
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+/// One operation, nothing for a blank line, or why it could not be read.
+pub type Record = Result<Option<Input>, LineError>;
+
+/// The operations in `input`. JSON Lines are read as they come, the other
+/// formats once the whole input is in.
+pub fn records<'a>(
+    format: Format,
+    mut input: impl BufRead + 'a,
+) -> Box<dyn Iterator<Item = Record> + 'a> {
+    let format = match format {
+        Format::Auto => detect(&mut input),
+        format => format,
+    };
+    match format {
+        Format::Auto | Format::Jsonl => Box::new(
+            input
+                .lines()
+                .enumerate()
+                .map(|(index, line)| parse_line(index + 1, line)),
+        ),
+        Format::Json => Box::new(json_array(input).into_iter()),
+        Format::Csv => Box::new(csv_rows(input).into_iter()),
+        Format::MessagePack => Box::new(message_pack(input).into_iter()),
+    }
+}
+
+// Guessed from the start of the input: JSON starts with `[` or `{`,
+// MessagePack with a map marker, and CSV with a header naming a `type` or
+// `operation` column. Anything else is read as JSON Lines, so a bad first
+// line is reported like any other.
+fn detect(input: &mut impl BufRead) -> Format {
+    let Ok(buffer) = input.fill_buf() else {
+        return Format::Jsonl;
+    };
+    match buffer.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'[') => Format::Json,
+        Some(0x80..=0x8f | 0xde | 0xdf) => Format::MessagePack,
+        Some(b'{') | None => Format::Jsonl,
+        Some(_) if csv_header(buffer) => Format::Csv,
+        Some(_) => Format::Jsonl,
+    }
+}
+
+fn csv_header(buffer: &[u8]) -> bool {
+    let first_line = buffer.split(|b| *b == b'\n').next().unwrap_or_default();
+    String::from_utf8_lossy(first_line)
+        .split(',')
+        .any(|header| {
+            let header = header.trim().trim_matches('"').to_lowercase();
+            header == "type" || header == "operation"
+        })
+}
+
+// Blank lines are not an error, there is just nothing on them.
+fn parse_line(number: usize, line: io::Result<String>) -> Record {
+    let data = line.map_err(|err| unreadable(number, err))?;
+    if data.trim().is_empty() {
+        return Ok(None);
+    }
+    serde_json::from_str::<Input>(&data)
+        .map(Some)
+        .map_err(|err| invalid(number, &err))
+}
+
+fn json_array(mut input: impl Read) -> Vec<Record> {
+    let mut json = String::new();
+    if let Err(err) = input.read_to_string(&mut json) {
+        return vec![Err(unreadable(0, err))];
+    }
+    match serde_json::from_str::<Vec<Value>>(&json) {
+        Ok(operations) => operations
+            .into_iter()
+            .enumerate()
+            .map(|(index, operation)| {
+                serde_json::from_value(operation)
+                    .map(Some)
+                    .map_err(|err| invalid(index + 1, &err))
+            })
+            .collect(),
+        Err(err) => vec![Err(LineError {
+            line: err.line(),
+            column: err.column(),
+            message: message(&err),
+        })],
+    }
+}
+
+// The `type` (or `operation`) column names the operation, the other columns
+// its fields. Headers may use `_` or spaces for `-`, in any case, and empty
+// cells are left out.
+fn csv_rows(input: impl Read) -> Vec<Record> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(input);
+    let headers: Vec<String> = match reader.headers() {
+        Ok(headers) => headers
+            .iter()
+            .map(|header| header.to_lowercase().replace(['_', ' '], "-"))
+            .collect(),
+        Err(err) => return vec![Err(unreadable(1, err))],
+    };
+    reader
+        .records()
+        .map(|row| {
+            let row = row.map_err(|err| {
+                let line = err.position().map_or(0, |p| p.line() as usize);
+                unreadable(line, err)
+            })?;
+            let line = row.position().map_or(0, |p| p.line() as usize);
+            csv_row(line, &headers, &row)
+        })
+        .collect()
+}
+
+fn csv_row(line: usize, headers: &[String], row: &csv::StringRecord) -> Record {
+    let mut operation = None;
+    let mut fields = Map::new();
+    for (header, cell) in headers.iter().zip(row.iter()) {
+        match (header.as_str(), cell) {
+            (_, "") => (),
+            ("type" | "operation", _) => operation = Some(cell.to_string()),
+            ("amount" | "available-limit", _) => {
+                let number = cell.parse::<u64>().map(Value::from);
+                fields.insert(header.clone(), number.unwrap_or_else(|_| json!(cell)));
+            }
+            ("active-card" | "active" | "allow-listed", _) => {
+                let flag = cell.parse::<bool>().map(Value::from);
+                fields.insert(header.clone(), flag.unwrap_or_else(|_| json!(cell)));
+            }
+            _ => {
+                fields.insert(header.clone(), json!(cell));
+            }
+        }
+    }
+    let Some(operation) = operation else {
+        if fields.is_empty() {
+            return Ok(None);
+        }
+        return Err(LineError {
+            line,
+            column: 0,
+            message: "no operation in the type column".to_string(),
+        });
+    };
+    serde_json::from_value(json!({ operation: fields }))
+        .map(Some)
+        .map_err(|err| invalid(line, &err))
+}
+
+// Operations one after the other. Reading stops at the first one that
+// can't be read, there is no telling where the next one starts.
+fn message_pack(mut input: impl Read) -> Vec<Record> {
+    let mut bytes = vec![];
+    if let Err(err) = input.read_to_end(&mut bytes) {
+        return vec![Err(unreadable(0, err))];
+    }
+    let mut cursor = Cursor::new(&bytes);
+    let mut records = vec![];
+    while (cursor.position() as usize) < bytes.len() {
+        match rmp_serde::from_read::<_, Input>(&mut cursor) {
+            Ok(operation) => records.push(Ok(Some(operation))),
+            Err(err) => {
+                records.push(Err(unreadable(records.len() + 1, err)));
+                break;
+            }
+        }
+    }
+    records
+}
+
+fn unreadable(line: usize, err: impl fmt::Display) -> LineError {
+    LineError {
+        line,
+        column: 0,
+        message: err.to_string(),
+    }
+}
+
+fn invalid(line: usize, err: &serde_json::Error) -> LineError {
+    LineError {
+        line,
+        column: err.column(),
+        message: message(err),
+    }
+}
+
+// The position serde appends is relative to what was parsed, not to the
+// input, so it's dropped.
+fn message(err: &serde_json::Error) -> String {
+    let position = format!(" at line {} column {}", err.line(), err.column());
+    let message = err
+        .to_string()
+        .replacen("unknown variant", "unknown operation", 1);
+    message
+        .strip_suffix(&position)
+        .unwrap_or(&message)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Account, Transaction};
+    use time_macros::datetime;
+
+    fn operations(format: Format, input: &[u8]) -> Vec<Value> {
+        records(format, input)
+            .map(|record| serde_json::to_value(record.unwrap()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn every_format_reads_the_same_operations() {
+        let jsonl = br#"{"account": {"active-card": true, "available-limit": 100}}
+{"transaction": {"merchant": "Burger King", "amount": 20, "time": "2019-02-13T10:00:00Z"}}
+"#;
+        let json = br#"[
+  {"account": {"active-card": true, "available-limit": 100}},
+  {"transaction": {"merchant": "Burger King", "amount": 20, "time": "2019-02-13T10:00:00Z"}}
+]"#;
+        let csv = b"Type,Active Card,available_limit,merchant,amount,time
+account,true,100,,,
+transaction,,,Burger King,20,2019-02-13T10:00:00Z
+";
+        let mut msgpack = vec![];
+        for operation in [
+            Input::Account(Account {
+                active_card: true,
+                available_limit: 100,
+                allow_listed: false,
+                blocked: false,
+            }),
+            Input::Transaction(Transaction {
+                merchant: "Burger King".to_string(),
+                amount: 20,
+                time: datetime!(2019-02-13 10:00:00 UTC),
+            }),
+        ] {
+            msgpack.extend(rmp_serde::to_vec_named(&operation).unwrap());
+        }
+
+        let expected = operations(Format::Jsonl, jsonl);
+        assert_eq!(expected.len(), 2);
+        for input in [&jsonl[..], json, csv, &msgpack] {
+            assert_eq!(operations(Format::Auto, input), expected);
+        }
+    }
+
+    #[test]
+    fn lines_are_parsed_one_by_one() {
+        assert!(parse_line(1, Ok("  ".to_string())).unwrap().is_none());
+        assert!(matches!(
+            parse_line(
+                1,
+                Ok(r#"{"account": {"active-card": true, "available-limit": 100}}"#.to_string())
+            ),
+            Ok(Some(Input::Account(_)))
+        ));
+        assert_eq!(
+            parse_line(7, Ok(r#"{"account": {"active-card": "yes"}}"#.to_string()))
+                .unwrap_err()
+                .to_string(),
+            "line 7, column 33: invalid type: string \"yes\", expected a boolean"
+        );
+        let err = io::Error::new(
+            io::ErrorKind::InvalidData,
+            "stream did not contain valid UTF-8",
+        );
+        assert_eq!(
+            parse_line(2, Err(err)).unwrap_err().to_string(),
+            "line 2, column 0: stream did not contain valid UTF-8"
+        );
+    }
+
+    #[test]
+    fn bad_first_line_is_still_read_as_json_lines() {
+        let jsonl = br#"oops
+{"account": {"active-card": true, "available-limit": 100}}
+"#;
+        let read: Vec<_> = records(Format::Auto, &jsonl[..]).collect();
+        assert_eq!(read.len(), 2);
+        assert_eq!(
+            read[0].as_ref().unwrap_err().to_string(),
+            "line 1, column 1: expected value"
+        );
+        assert!(matches!(read[1], Ok(Some(Input::Account(_)))));
+
+        let read: Vec<_> = records(Format::Auto, &b"x"[..]).collect();
+        assert!(read[0].is_err());
+    }
+
+    #[test]
+    fn unreadable_records_are_located() {
+        let csv = b"type,merchant,amount,time
+transaction,Burger King,twenty,2019-02-13T10:00:00Z
+,Burger King,20,2019-02-13T10:00:00Z
+";
+        let errors: Vec<String> = records(Format::Csv, &csv[..])
+            .map(|record| record.unwrap_err().to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "line 2, column 0: invalid type: string \"twenty\", expected u32",
+                "line 3, column 0: no operation in the type column",
+            ]
+        );
+
+        let json = br#"[{"transaction": {}}, {"teleport": {}}]"#;
+        let errors: Vec<String> = records(Format::Json, &json[..])
+            .map(|record| record.unwrap_err().to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "line 1, column 0: missing field `merchant`",
+                "line 2, column 0: unknown operation `teleport`, expected one of `account`, `transaction`, `refund`, `card-block`, `card-unblock`, `limit-update`, `allow-listed`",
+            ]
+        );
+    }
+}
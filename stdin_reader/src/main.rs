@@ -1,23 +1,68 @@
+mod authorizer;
+mod input;
+mod output;
+
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
-use std::io;
-use std::io::BufRead;
+use std::io::{self, BufRead, Write};
+use std::process;
+use std::str::FromStr;
 use time::format_description::well_known::Rfc3339;
 use time::OffsetDateTime;
 
+use crate::authorizer::Authorizer;
+use crate::output::Output;
+
 // Usage:
 // cargo run < ./operations.jsonl
+// cargo run -- --on-error emit < ./operations.jsonl
+//
+// Every operation is authorized against the account created by the first
+// `account` line, and printed with its outcome as one JSON line:
+// {"operation": {"transaction": {...}}, "account": {"active-card": true, "available-limit": 80}, "violations": []}
+//
+// Operations, one per line:
+// {"account": {"active-card": true, "available-limit": 100}}
+// {"transaction": {"merchant": "Burger King", "amount": 20, "time": "2019-02-13T10:00:00.000Z"}}
+// {"refund": {"merchant": "Burger King", "amount": 20, "time": "2019-02-13T11:00:00.000Z"}}
+// {"card-block": {"reason": "lost"}}
+// {"card-unblock": {"reason": "found"}}
+// {"limit-update": {"available-limit": 500}}
+// {"allow-listed": {"active": true}}
+//
+// `--input` is one of jsonl, json (a single array of operations), csv (with
+// a `type` column naming the operation and one column per field) or msgpack
+// (one operation after the other). By default it's guessed from the input.
+//
+// Records that can't be read are reported with their line and column, then
+// handled according to `--on-error`:
+//   fail-fast  stop at the first one
+//   skip       report it on stderr and go on (the default)
+//   emit       print an error record in its place and go on
+// The exit code is 1 if any record could not be read, 2 on bad arguments.
+//
+// `--output` picks how outcomes and error records are printed: jsonl (the
+// default), pretty, csv or text. Every format prints the operation next to
+// its outcome.
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Account {
     #[serde(rename = "active-card")]
     active_card: bool,
     #[serde(rename = "available-limit")]
     available_limit: u32,
+    #[serde(rename = "allow-listed", default, skip_serializing_if = "is_false")]
+    allow_listed: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    blocked: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
 }
 
 #[serde_as]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Transaction {
     merchant: String,
     amount: u32,
@@ -25,31 +70,255 @@ pub struct Transaction {
     time: OffsetDateTime,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Gives back the amount of an earlier transaction with the same merchant
+/// and amount.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Refund {
+    merchant: String,
+    amount: u32,
+    #[serde_as(as = "Rfc3339")]
+    time: OffsetDateTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CardBlock {
+    #[serde(default)]
+    reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CardUnblock {
+    #[serde(default)]
+    reason: String,
+}
+
+/// Replaces the available limit.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LimitUpdate {
+    #[serde(rename = "available-limit")]
+    available_limit: u32,
+}
+
+/// Puts the account on or takes it off the allow list, which is reported
+/// with the account.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AllowList {
+    active: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 enum Input {
     #[serde(rename = "account")]
     Account(Account),
     #[serde(rename = "transaction")]
     Transaction(Transaction),
+    #[serde(rename = "refund")]
+    Refund(Refund),
+    #[serde(rename = "card-block")]
+    CardBlock(CardBlock),
+    #[serde(rename = "card-unblock")]
+    CardUnblock(CardUnblock),
+    #[serde(rename = "limit-update")]
+    LimitUpdate(LimitUpdate),
+    #[serde(rename = "allow-listed")]
+    AllowListed(AllowList),
 }
 
-fn main() {
-    for line in io::stdin().lock().lines() {
-        if let Ok(data) = line {
-            let deserialization_result: Result<Input, serde_json::Error> =
-                serde_json::from_str::<Input>(&data);
-            let res: Input = deserialization_result.expect("This should not panic");
-
-            match res {
-                Input::Account(res) => println!(
-                    "<Account>: active_card: {:?}, available_limit: {:?}",
-                    res.active_card, res.available_limit
-                ),
-                Input::Transaction(res) => println!(
-                    "<Transaction>: merchant: {:?}, amount: {:?}, time: {:?}",
-                    res.merchant, res.amount, res.time
-                ),
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorPolicy {
+    FailFast,
+    Skip,
+    Emit,
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "fail-fast" => Ok(ErrorPolicy::FailFast),
+            "skip" => Ok(ErrorPolicy::Skip),
+            "emit" => Ok(ErrorPolicy::Emit),
+            _ => Err(format!("unknown error policy {:?}", policy)),
+        }
+    }
+}
+
+struct Options {
+    on_error: ErrorPolicy,
+    input: input::Format,
+    output: output::Format,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        on_error: ErrorPolicy::Skip,
+        input: input::Format::Auto,
+        output: output::Format::Jsonl,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--on-error" => {
+                options.on_error = args
+                    .next()
+                    .ok_or("--on-error needs fail-fast, skip or emit")?
+                    .parse()?
+            }
+            "--input" => {
+                options.input = args
+                    .next()
+                    .ok_or("--input needs auto, jsonl, json, csv or msgpack")?
+                    .parse()?
             }
+            "--output" => {
+                options.output = args
+                    .next()
+                    .ok_or("--output needs jsonl, pretty, csv or text")?
+                    .parse()?
+            }
+            _ => return Err(format!("unknown argument {:?}", arg)),
         }
     }
+    Ok(options)
+}
+
+fn main() {
+    let options = parse_args(std::env::args().skip(1)).unwrap_or_else(|reason| {
+        eprintln!("{}", reason);
+        process::exit(2);
+    });
+    let mut output = Output::new(options.output, io::stdout().lock());
+    let summary =
+        run(&options, io::stdin().lock(), &mut output, &mut io::stderr()).unwrap_or_else(|err| {
+            eprintln!("could not write output: {}", err);
+            process::exit(1);
+        });
+
+    if summary.failures > 0 {
+        eprintln!(
+            "{} of {} records could not be read",
+            summary.failures, summary.records
+        );
+        process::exit(1);
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Summary {
+    records: usize, // blank lines are not records
+    failures: usize,
+}
+
+// Authorizes every record in `input` and writes its outcome to `output`.
+// Records that can't be read are reported on `errors` or `output`, as the
+// error policy says.
+fn run<W: Write>(
+    options: &Options,
+    input: impl BufRead,
+    output: &mut Output<W>,
+    errors: &mut impl Write,
+) -> io::Result<Summary> {
+    let mut authorizer = Authorizer::default();
+    let mut summary = Summary {
+        records: 0,
+        failures: 0,
+    };
+    for record in input::records(options.input, input) {
+        let res = match record {
+            Ok(Some(res)) => res,
+            Ok(None) => continue,
+            Err(err) => {
+                summary.records += 1;
+                summary.failures += 1;
+                match options.on_error {
+                    ErrorPolicy::FailFast => {
+                        writeln!(errors, "{}", err)?;
+                        break;
+                    }
+                    ErrorPolicy::Skip => writeln!(errors, "{}", err)?,
+                    ErrorPolicy::Emit => output.error(&err)?,
+                }
+                continue;
+            }
+        };
+
+        summary.records += 1;
+        let outcome = authorizer.apply(res.clone());
+        output.outcome(&res, &outcome)?;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPERATIONS: &str = r#"{"account": {"active-card": true, "available-limit": 100}}
+
+{"transaction": {"merchant": "Burger King", "amount": 20}}
+{"transaction": {"merchant": "Burger King", "amount": 20, "time": "2019-02-13T10:00:00Z"}}
+"#;
+
+    // What `run` prints to the output and to the errors with `on_error`.
+    fn run_with(on_error: ErrorPolicy) -> (Summary, Vec<String>, String) {
+        let options = Options {
+            on_error,
+            input: input::Format::Jsonl,
+            output: output::Format::Text,
+        };
+        let mut out = vec![];
+        let mut errors = vec![];
+        let summary = run(
+            &options,
+            OPERATIONS.as_bytes(),
+            &mut Output::new(options.output, &mut out),
+            &mut errors,
+        )
+        .unwrap();
+        let lines = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect();
+        (summary, lines, String::from_utf8(errors).unwrap())
+    }
+
+    #[test]
+    fn fail_fast_stops_at_the_first_error() {
+        let (summary, lines, errors) = run_with(ErrorPolicy::FailFast);
+        assert_eq!(
+            summary,
+            Summary {
+                records: 2,
+                failures: 1
+            }
+        );
+        assert_eq!(lines.len(), 2);
+        assert_eq!(errors, "line 3, column 57: missing field `time`\n");
+    }
+
+    #[test]
+    fn skip_reports_errors_and_goes_on() {
+        let (summary, lines, errors) = run_with(ErrorPolicy::Skip);
+        assert_eq!(
+            summary,
+            Summary {
+                records: 3,
+                failures: 1
+            }
+        );
+        assert_eq!(lines.len(), 4);
+        assert!(lines[3].contains("available_limit: 80"));
+        assert_eq!(errors, "line 3, column 57: missing field `time`\n");
+    }
+
+    #[test]
+    fn emit_prints_error_records_in_place() {
+        let (summary, lines, errors) = run_with(ErrorPolicy::Emit);
+        assert_eq!(summary.failures, 1);
+        assert_eq!(lines[2], "<Error>: line 3, column 57: missing field `time`");
+        assert_eq!(lines.len(), 5);
+        assert!(errors.is_empty());
+    }
 }
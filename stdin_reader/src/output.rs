@@ -0,0 +1,284 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use crate::authorizer::{Outcome, Violation};
+use crate::input::LineError;
+use crate::Input;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Jsonl,
+    Pretty,
+    Csv,
+    Text,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "jsonl" => Ok(Format::Jsonl),
+            "pretty" => Ok(Format::Pretty),
+            "csv" => Ok(Format::Csv),
+            "text" => Ok(Format::Text),
+            _ => Err(format!("unknown output format {:?}", format)),
+        }
+    }
+}
+
+// One JSON record: the operation as it was read, and its outcome.
+#[derive(Serialize)]
+struct Record<'a> {
+    operation: &'a Input,
+    #[serde(flatten)]
+    outcome: &'a Outcome,
+}
+
+// One CSV row, either an operation with its outcome or an error record.
+#[derive(Serialize, Default)]
+struct Row {
+    operation: Option<String>,
+    merchant: Option<String>,
+    amount: Option<u64>,
+    time: Option<String>,
+    #[serde(rename = "active-card")]
+    active_card: Option<bool>,
+    #[serde(rename = "available-limit")]
+    available_limit: Option<u32>,
+    #[serde(rename = "allow-listed")]
+    allow_listed: Option<bool>,
+    blocked: Option<bool>,
+    violations: String, // separated by `;`
+    error: Option<String>,
+}
+
+/// Writes outcomes and error records in the chosen format.
+pub struct Output<W: Write> {
+    format: Format,
+    out: W,
+    header_written: bool,
+}
+
+impl<W: Write> Output<W> {
+    pub fn new(format: Format, out: W) -> Self {
+        Output {
+            format,
+            out,
+            header_written: false,
+        }
+    }
+
+    pub fn outcome(&mut self, operation: &Input, outcome: &Outcome) -> io::Result<()> {
+        let record = Record { operation, outcome };
+        match self.format {
+            Format::Jsonl => self.json_line(&record),
+            Format::Pretty => self.pretty(&record),
+            Format::Csv => {
+                let (kind, fields) = fields(operation);
+                self.csv_row(Row {
+                    merchant: fields["merchant"].as_str().map(str::to_string),
+                    amount: fields["amount"].as_u64(),
+                    time: fields["time"].as_str().map(str::to_string),
+                    operation: Some(kind),
+                    active_card: outcome.account.as_ref().map(|a| a.active_card),
+                    available_limit: outcome.account.as_ref().map(|a| a.available_limit),
+                    allow_listed: outcome.account.as_ref().map(|a| a.allow_listed),
+                    blocked: outcome.account.as_ref().map(|a| a.blocked),
+                    violations: codes(&outcome.violations).join(";"),
+                    error: None,
+                })
+            }
+            Format::Text => {
+                text(&mut self.out, operation)?;
+                write!(self.out, "  ")?;
+                match &outcome.account {
+                    Some(account) => write!(
+                        self.out,
+                        "<Account>: active_card: {:?}, available_limit: {:?}, allow_listed: {:?}, blocked: {:?}",
+                        account.active_card, account.available_limit, account.allow_listed, account.blocked
+                    )?,
+                    None => write!(self.out, "<Account>: none")?,
+                }
+                writeln!(self.out, ", violations: {:?}", codes(&outcome.violations))
+            }
+        }
+    }
+
+    pub fn error(&mut self, err: &LineError) -> io::Result<()> {
+        let record = serde_json::json!({ "error": err });
+        match self.format {
+            Format::Jsonl => self.json_line(&record),
+            Format::Pretty => self.pretty(&record),
+            Format::Csv => self.csv_row(Row {
+                error: Some(err.to_string()),
+                ..Row::default()
+            }),
+            Format::Text => writeln!(self.out, "<Error>: {}", err),
+        }
+    }
+
+    fn json_line(&mut self, value: &impl Serialize) -> io::Result<()> {
+        serde_json::to_writer(&mut self.out, value)?;
+        writeln!(self.out)
+    }
+
+    fn pretty(&mut self, value: &impl Serialize) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut self.out, value)?;
+        writeln!(self.out)
+    }
+
+    fn csv_row(&mut self, row: Row) -> io::Result<()> {
+        let mut csv = csv::WriterBuilder::new()
+            .has_headers(!self.header_written)
+            .from_writer(&mut self.out);
+        csv.serialize(row)?;
+        csv.flush()?;
+        self.header_written = true;
+        Ok(())
+    }
+}
+
+fn codes(violations: &[Violation]) -> Vec<String> {
+    violations.iter().map(Violation::code).collect()
+}
+
+// The name the operation is read under, and its fields.
+fn fields(operation: &Input) -> (String, Value) {
+    match serde_json::to_value(operation) {
+        Ok(Value::Object(operation)) => operation.into_iter().next().unwrap(),
+        _ => unreachable!("operations serialize to an object named after them"),
+    }
+}
+
+// The operation as the reader has always printed it.
+fn text(out: &mut impl Write, operation: &Input) -> io::Result<()> {
+    match operation {
+        Input::Account(account) => writeln!(
+            out,
+            "<Account>: active_card: {:?}, available_limit: {:?}",
+            account.active_card, account.available_limit
+        ),
+        Input::Transaction(transaction) => writeln!(
+            out,
+            "<Transaction>: merchant: {:?}, amount: {:?}, time: {:?}",
+            transaction.merchant, transaction.amount, transaction.time
+        ),
+        Input::Refund(refund) => writeln!(
+            out,
+            "<Refund>: merchant: {:?}, amount: {:?}, time: {:?}",
+            refund.merchant, refund.amount, refund.time
+        ),
+        Input::CardBlock(block) => writeln!(out, "<CardBlock>: reason: {:?}", block.reason),
+        Input::CardUnblock(unblock) => {
+            writeln!(out, "<CardUnblock>: reason: {:?}", unblock.reason)
+        }
+        Input::LimitUpdate(update) => writeln!(
+            out,
+            "<LimitUpdate>: available_limit: {:?}",
+            update.available_limit
+        ),
+        Input::AllowListed(allow_list) => {
+            writeln!(out, "<AllowListed>: active: {:?}", allow_list.active)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authorizer::Authorizer;
+    use crate::input::{self, records};
+
+    const OPERATIONS: &[u8] = br#"{"account": {"active-card": true, "available-limit": 100}}
+{"transaction": {"merchant": "Burger King", "amount": 120, "time": "2019-02-13T10:00:00Z"}}
+{"card-block": {}}
+"#;
+
+    // What each format prints for `OPERATIONS`.
+    fn printed(format: Format) -> String {
+        let mut authorizer = Authorizer::default();
+        let mut output = Output::new(format, vec![]);
+        for record in records(input::Format::Jsonl, OPERATIONS) {
+            let operation = record.unwrap().unwrap();
+            let outcome = authorizer.apply(operation.clone());
+            output.outcome(&operation, &outcome).unwrap();
+        }
+        String::from_utf8(output.out).unwrap()
+    }
+
+    #[test]
+    fn jsonl_prints_each_operation_with_its_outcome() {
+        let lines: Vec<serde_json::Value> = printed(Format::Jsonl)
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines[1],
+            serde_json::json!({
+                "operation": {"transaction": {"merchant": "Burger King", "amount": 120, "time": "2019-02-13T10:00:00Z"}},
+                "account": {"active-card": true, "available-limit": 100},
+                "violations": ["insufficient-limit"],
+            })
+        );
+        assert_eq!(lines.len(), 3);
+
+        let pretty: Vec<serde_json::Value> =
+            serde_json::Deserializer::from_str(&printed(Format::Pretty))
+                .into_iter()
+                .collect::<Result<_, _>>()
+                .unwrap();
+        assert_eq!(pretty, lines);
+    }
+
+    #[test]
+    fn csv_writes_the_header_once() {
+        assert_eq!(
+            printed(Format::Csv),
+            "operation,merchant,amount,time,active-card,available-limit,allow-listed,blocked,violations,error
+account,,,,true,100,false,false,,
+transaction,Burger King,120,2019-02-13T10:00:00Z,true,100,false,false,insufficient-limit,
+card-block,,,,true,100,false,true,,
+"
+        );
+    }
+
+    #[test]
+    fn text_prints_each_operation_then_its_outcome() {
+        assert_eq!(
+            printed(Format::Text),
+            r#"<Account>: active_card: true, available_limit: 100
+  <Account>: active_card: true, available_limit: 100, allow_listed: false, blocked: false, violations: []
+<Transaction>: merchant: "Burger King", amount: 120, time: 2019-02-13 10:00:00.0 +00:00:00
+  <Account>: active_card: true, available_limit: 100, allow_listed: false, blocked: false, violations: ["insufficient-limit"]
+<CardBlock>: reason: ""
+  <Account>: active_card: true, available_limit: 100, allow_listed: false, blocked: true, violations: []
+"#
+        );
+    }
+
+    #[test]
+    fn errors_are_records_too() {
+        let err = records(input::Format::Jsonl, &b"oops"[..])
+            .next()
+            .unwrap()
+            .unwrap_err();
+        let mut output = Output::new(Format::Csv, vec![]);
+        output.error(&err).unwrap();
+        let mut jsonl = Output::new(Format::Jsonl, vec![]);
+        jsonl.error(&err).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output.out).unwrap(),
+            "operation,merchant,amount,time,active-card,available-limit,allow-listed,blocked,violations,error
+,,,,,,,,,\"line 1, column 1: expected value\"
+"
+        );
+        assert_eq!(
+            String::from_utf8(jsonl.out).unwrap(),
+            "{\"error\":{\"column\":1,\"line\":1,\"message\":\"expected value\"}}\n"
+        );
+    }
+}